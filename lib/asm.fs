@@ -88,29 +88,14 @@ x21 const %sm
     swap 20 lshift +
     ;
 
-: abc_bca
-  abc_cab
-  abc_cab
-;
-
 \ offset -> mapped value
+\ imm[20|10:1|11|19:12]
 : jal_imm_map_ 
-  dup
-  12 rshift b11111111 & \ 19:12
-  swap
-  dup
-  11 rshift b1 & \ 11
-  8 lshift
-  abc_bca
-  +
-  swap dup
-  1 rshift b1111111111 & \ 10:1
-  9 lshift
-  abc_bca
-  +
-  swap
-  20 rshift b1 & 19 lshift
-  +
+  0
+  over 19 12 bits 7 0 set_bits
+  over 11 11 bits 8 8 set_bits
+  over 10 1 bits 18 9 set_bits
+  swap 20 20 bits 19 19 set_bits
 ;
 \ offset rd
 : jal_ 
@@ -122,17 +107,13 @@ x21 const %sm
   +
 ;
 
+\ offset -> instruction bits
 : branch_imm_map_
-  dup 11 rshift x1 & 7 lshift
-  swap
-  dup 1 rshift x1F & 8 lshift
-  swap
-  dup 5 rshift x3F & 25 lshift 
-  swap
-  12 rshift x1 & 31 lshift
-  +
-  +
-  +
+  0
+  over 11 11 bits 7 7 set_bits
+  over 4 1 bits 11 8 set_bits
+  over 10 5 bits 30 25 set_bits
+  swap 12 12 bits 31 31 set_bits
 ;
 
 \ imm s1 s2 f3 
@@ -217,7 +198,7 @@ x21 const %sm
 ;
 
 "loading hello" .
-helloworld stack_to_list #= hello
+~[ helloworld ] #= hello
 "data.bin" write_bin
 
 \ TODO clean up this thing
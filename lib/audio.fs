@@ -1,7 +1,4 @@
-44100 const SampleRate
-: freqtosample SampleRate swap / ;
+\ Oscillators are builtins, as in 440 0.5 0 square ( freq amp phase -- gen )
 
-\ amp freq phase square
-: normalramp natural + swap freqtosample dup swap abc_cab % swap tofloat / ;
-: ramp normalramp swap tofloat * ;
-: square normalramp swap tofloat swap 0.5 > * ;
\ No newline at end of file
+\ freq -- samples per cycle
+: freqtosample sample_rate swap / ;
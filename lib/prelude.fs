@@ -1,9 +1,5 @@
 \ Common macros
 
-\ Stack operations
-: ?dup dup if dup then ;
-: tuck dup abc_cab ;
-
 \ Basic math
 : 2/ 2 / ;
 : pow2 ( n -- n ) 1 swap lshift ;
@@ -33,6 +29,9 @@
 \ (n n msg --> None if n==n, throws error if not)
 : assert_equal abc_cab == swap assert ;
 
+\ (xt msg --> None if xt throws, throws error if not)
+: assert_throws swap catch 0 == 0 == swap assert ;
+
 \ Math for getting response
 : slicedata dup 8 access swap dup 7 access swap dup 6 access swap 5 access ;
 : 8to32 24 lshift swap 16 lshift + swap 8 lshift + + ;
@@ -45,8 +45,10 @@ impl AudioContext{
             None => {return Err(AudioError::None);}
         };
         
-        for i in 0..buffer.len(){
-            buffer[i] += channel.nextf();
+        let mut block = [0.0; BUFFER_SIZE];
+        channel.fill(&mut block);
+        for (out, x) in buffer.iter_mut().zip(block){
+            *out += x;
         }
         
         Ok(())
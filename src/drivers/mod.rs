@@ -3,7 +3,7 @@ use std::thread;
 
 use serialport::SerialPort;
 use std::sync::MutexGuard;
-use crate::types::ForthVal;
+use crate::types::{ErrKind, ForthErr, ForthRet, ForthVal};
 
 
 use crate::reg;
@@ -11,18 +11,20 @@ use std::fs;
 use std::error::Error;
 
 /// Convert forth value into bytes
-fn to_bytes(v: &ForthVal) -> Vec<u8>{
+/// Strings are sent as UTF-8 and byte strings as they are
+fn to_bytes(v: &ForthVal) -> Result<Vec<u8>, ForthErr>{
    match v{
-       ForthVal::Str(s) => s.as_bytes().to_vec(),
-       ForthVal::Int(v) => (*v as u32).to_be_bytes().to_vec(),
+       ForthVal::Str(s) => Ok(s.as_bytes().to_vec()),
+       ForthVal::Bytes(b) => Ok(b.clone()),
+       ForthVal::Int(_) => Ok(v.to_u32()?.to_be_bytes().to_vec()),
        ForthVal::List(mlist) => {
         let mut result = Vec::new();
         for lv in mlist{
-            result.append(&mut to_bytes(lv));
+            result.append(&mut to_bytes(lv)?);
         }
-        result
+        Ok(result)
        },
-       _ => panic!("Can't convert {:?} to bytes", v)
+       _ => Err(ForthErr::mismatch("bytes", v))
    }
 }
 
@@ -77,23 +79,25 @@ impl Serial{
         }
     }
     
-    pub fn print_ports() -> ForthVal{
+    pub fn print_ports() -> ForthRet{
         let mut ports = Vec::new();
         
-        for p in serialport::available_ports().expect("No ports found"){
+        let available = serialport::available_ports()
+            .map_err(|e| ForthErr::new(ErrKind::Device, format!("{:?}", e)))?;
+        for p in available{
             //println!("{}", p.port_name);
             ports.push(ForthVal::Str(p.port_name));
         }
         
-        ForthVal::List(ports)
+        Ok(ForthVal::List(ports))
     }
     
     pub fn available(&self) -> bool{
         self.port.lock().unwrap().is_some()
     }
     
-    pub fn start(&mut self, p: &ForthVal, b: &ForthVal) -> ForthVal{
-        let baud = b.to_int().unwrap() as u32;
+    pub fn start(&mut self, p: &ForthVal, b: &ForthVal) -> ForthRet{
+        let baud = b.to_int()? as u32;
         match p{
             ForthVal::Str(s) => {
                 println!("Connecting to serial port {} with baud {}", s, baud);
@@ -103,33 +107,34 @@ impl Serial{
                 let mut sp = self.port.lock().unwrap();
                 *sp = match s{
                     Ok(p) => Some(p),
-                    Err(e) => {return ForthVal::Err(format!("{:?}", e));}
+                    Err(e) => {return Err(ForthErr::new(ErrKind::Device, format!("{:?}", e)));}
                 }
             }
             _ => {
-                return ForthVal::Err(format!("Unable to open {p:?}"));
+                return Err(ForthErr::mismatch("port name", p));
             }
         }
-        ForthVal::Null
+        Ok(ForthVal::Null)
     }
     
-    pub fn put(&mut self, msg: &ForthVal) -> ForthVal{
-        let bytes = to_bytes(msg);
+    pub fn put(&mut self, msg: &ForthVal) -> ForthRet{
+        let bytes = to_bytes(msg)?;
         
-        if self.available(){
-            let mut sp = self.port.lock().unwrap();
-            let _wlen = (sp.as_mut())
-                    .expect("Serial port exists")
-                    .write(bytes.as_slice()).expect("Write failed");
-        }
-        else{
-            // temp tell me the info
-            return ForthVal::Err(format!("Port not open"));
+        let mut sp = self.port.lock().unwrap();
+        match sp.as_mut(){
+            Some(port) => {
+                if let Err(e) = port.write(bytes.as_slice()){
+                    return Err(ForthErr::new(ErrKind::Device, format!("{:?}", e)));
+                }
+            },
+            None => {
+                return Err(ForthErr::new(ErrKind::Device, "Port not open"));
+            }
         }
-        return ForthVal::Null;
+        Ok(ForthVal::Null)
     }
     
-    pub fn get(&mut self) -> ForthVal{
+    pub fn get(&mut self) -> ForthRet{
         if self.available(){
             let mut lock = self.port.lock().unwrap();
             
@@ -141,15 +146,15 @@ impl Serial{
                     for b in &mut serial_buf[0..rlen]{
                         resp.push(ForthVal::Int(*b as i64));
                     }
-                    return ForthVal::List(resp);
+                    return Ok(ForthVal::List(resp));
                 },
                 Err(e) => {
-                    return ForthVal::Err(format!("{:?}", e));
+                    return Err(ForthErr::new(ErrKind::Device, format!("{:?}", e)));
                 }
             }
         }
         else{
-            return ForthVal::Err(format!("Port not open"));
+            return Err(ForthErr::new(ErrKind::Device, "Port not open"));
         }
     }
 }
@@ -286,4 +291,20 @@ impl DeviceInterface for RiscMock{
         RiscMock::write_expand(&mut self.memory.lock().unwrap(), addr as usize, data);
         Ok(1)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::types::ForthVal;
+    use super::to_bytes;
+    
+    #[test]
+    fn escaped_bytes(){
+        let v = ForthVal::Bytes(vec![b'W', 0x01, 0xff]);
+        assert_eq!(to_bytes(&v).unwrap(), vec![b'W', 0x01, 0xff]);
+        let v = ForthVal::List(vec![ForthVal::Bytes(vec![0x80]), ForthVal::Int(2)]);
+        assert_eq!(to_bytes(&v).unwrap(), vec![0x80, 0, 0, 0, 2]);
+        // Text is always UTF-8
+        assert_eq!(to_bytes(&ForthVal::Str("W\x01é€".to_string())).unwrap(), vec![b'W', 0x01, 0xc3, 0xa9, 0xe2, 0x82, 0xac]);
+    }
+}
@@ -1,6 +1,6 @@
-use std::collections::HashMap;
+use std::{collections::{HashMap, VecDeque}, f64::consts::TAU, marker::PhantomData, rc::Rc};
 
-use crate::{interpreter::{Workspace, WorkspaceContext}, types::ForthVal};
+use crate::{interpreter::WorkspaceContext, types::{ErrKind, FloatOp, ForthErr, ForthVal, UnaryFloatOp}};
 
 /*
 Generate lazy lists
@@ -9,9 +9,29 @@ Generate lazy lists
 pub struct GenEnv{
     // Variables for forms
     pub var: HashMap<String, ForthVal>,
-    // Arguments from stack
+    // Arguments from stack, deepest first
+    // Numbers or generators which are advanced with this one
     pub args: Vec<ForthVal>,
-    pub counter: usize
+    // Value of each argument for the current sample
+    pub values: Vec<f64>,
+    pub counter: usize,
+    pub sample_rate: f64
+}
+
+impl GenEnv{
+    /// Value of an argument for the current sample
+    pub fn arg(&self, i: usize) -> f64{
+        self.values.get(i).copied().unwrap_or(f64::NAN)
+    }
+    
+    /// Take the next sample of generator arguments
+    fn advance(&mut self){
+        for (i, a) in self.args.iter_mut().enumerate(){
+            if let ForthVal::Generator(gen) = a{
+                self.values[i] = gen.nextf();
+            }
+        }
+    }
 }
 
 /// TODO add consume method to set parameters
@@ -23,13 +43,28 @@ pub trait Generator{
         ForthVal::Float(self.nextf(env))
     }
     fn make_clone(&self) -> Box<dyn Generator>;
+    /// Number of samples, None for endless generators
+    fn length(&self, _env: &GenEnv) -> Option<usize>{
+        None
+    }
+    /// Fill a block with the next samples
+    fn fill(&mut self, env: &mut GenEnv, block: &mut [f64]){
+        for x in block.iter_mut(){
+            env.advance();
+            *x = self.nextf(env);
+            env.counter += 1;
+        }
+    }
 }
 
+/// Operation on a block of samples
+pub type BlockOp = Rc<dyn Fn(&mut [f64])>;
+
 pub struct GeneratorUnit{
     pub env: GenEnv,
     pub gen: Box<dyn Generator>,
-    pub trace: Vec<ForthVal>,
-    pub ws: Workspace
+    // Operations done on the samples, in order
+    pub trace: Vec<BlockOp>
 }
 
 impl Clone for GeneratorUnit{
@@ -37,25 +72,70 @@ impl Clone for GeneratorUnit{
         Self{
             env: self.env.clone(),
             trace: self.trace.clone(),
-            ws: Workspace::new(),
             gen: self.gen.make_clone()
         }
     }
 }
 
 impl GeneratorUnit{
+    pub fn new(gen: Box<dyn Generator>) -> Self{
+        Self{
+            env: GenEnv::default(),
+            gen,
+            trace: Vec::new()
+        }
+    }
+    
+    /// Samples left, None for endless generators
+    pub fn remaining(&self) -> Option<usize>{
+        self.gen.length(&self.env).map(|n| n.saturating_sub(self.env.counter))
+    }
+    
+    pub fn ended(&self) -> bool{
+        self.remaining() == Some(0)
+    }
+    
     /// Get context from workspace
-    pub fn consume(&mut self, ws: &mut WorkspaceContext){
-        // TODO better error handling
-        self.env.args.clear();
-        for _i in 0..self.gen.num_args(){
-            self.env.args.push(ws.pop().unwrap());
+    pub fn consume(&mut self, ws: &mut WorkspaceContext) -> Result<(), ForthErr>{
+        let n = self.gen.num_args();
+        if ws.len() < n{
+            return Err(ForthErr::underflow());
+        }
+        // Arguments are all checked before any are popped
+        let mut values = Vec::with_capacity(n);
+        for i in ws.len() - n..ws.len(){
+            values.push(match ws.peek(i){
+                Some(ForthVal::Generator(_)) => f64::NAN,
+                Some(v) => v.to_float()?,
+                None => {return Err(ForthErr::underflow());}
+            });
         }
+        let mut args = Vec::with_capacity(n);
+        for _i in 0..n{
+            args.push(ws.pop()?);
+        }
+        args.reverse();
+        self.env.args = args;
+        self.env.values = values;
+        self.env.sample_rate = ws.sample_rate;
+        Ok(())
     }
     
-    /// Add operation on top of generator
-    pub fn push(&mut self, v: &ForthVal) -> &mut GeneratorUnit{
-        self.trace.push(v.clone());
+    /// Add operation with a constant, as ff(c, sample) or ff(sample, c) if the generator was on top
+    pub fn push_binary(&mut self, c: f64, ff: FloatOp, generator_on_top: bool) -> &mut GeneratorUnit{
+        let op: BlockOp = if generator_on_top{
+            Rc::new(move |block: &mut [f64]| block.iter_mut().for_each(|x| *x = ff(x, &c)))
+        }
+        else{
+            Rc::new(move |block: &mut [f64]| block.iter_mut().for_each(|x| *x = ff(&c, x)))
+        };
+        self.trace.push(op);
+        self
+    }
+    
+    /// Add operation on each sample
+    pub fn push_unary(&mut self, ff: UnaryFloatOp) -> &mut GeneratorUnit{
+        self.trace.push(Rc::new(move |block: &mut [f64]| block.iter_mut().for_each(|x| *x = ff(x))));
         self
     }
     
@@ -66,21 +146,23 @@ impl GeneratorUnit{
         result
     }
     
+    /// Next sample, NaN once a finite generator has ended
     pub fn nextf(&mut self) -> f64{
-        let result = self.gen.nextf(&self.env);
-        
-        self.env.counter += 1;
-        
-        if self.trace.len() > 0{
-            self.ws.ctx.push(ForthVal::Float(result.clone()));
-            
-            for v in &self.trace{
-                let _ = self.ws.run(v);
-            }
-            return self.ws.ctx.pop().unwrap().to_float().unwrap();
+        let mut result = [0.0];
+        self.fill(&mut result);
+        result[0]
+    }
+    
+    /// Fill a block with the next samples, NaN once a finite generator has ended
+    /// Each operation in the trace runs over the whole block
+    pub fn fill(&mut self, block: &mut [f64]){
+        let n = self.remaining().map_or(block.len(), |left| left.min(block.len()));
+        let (samples, after_end) = block.split_at_mut(n);
+        self.gen.fill(&mut self.env, samples);
+        for op in &self.trace{
+            op(samples);
         }
-        
-        result
+        after_end.fill(f64::NAN);
     }
 }
 
@@ -109,9 +191,10 @@ impl Generator for Natural{
     }
 }
 
+/// Counts from 0 to period - 1 and starts again
+/// ( period -- gen )
 #[derive(Default, Clone)]
 pub struct Ramp{
-    value: usize
 }
 
 impl Generator for Ramp{
@@ -119,16 +202,473 @@ impl Generator for Ramp{
         1
     }
     fn nextf(&mut self, env: &GenEnv) -> f64{
-        let period = env.args[0].to_int().unwrap() as usize; // TODO make more flexible
-        if self.value == period - 1{
-            self.value = 0;
+        let period = env.arg(0).max(1.0) as usize;
+        (env.counter % period) as f64
+    }
+    fn make_clone(&self) -> Box<dyn Generator> {
+        Box::new(Ramp::default())
+    }
+}
+
+/// Shape of a periodic wave, p is the position in the cycle from 0 to 1
+pub trait Wave{
+    // Pulse width is an extra argument
+    const PULSE: bool = false;
+    fn at(p: f64, width: f64) -> f64;
+}
+
+#[derive(Default, Clone)]
+pub struct Sine;
+impl Wave for Sine{
+    fn at(p: f64, _width: f64) -> f64{
+        (TAU * p).sin()
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Saw;
+impl Wave for Saw{
+    fn at(p: f64, _width: f64) -> f64{
+        2.0 * p - 1.0
+    }
+}
+
+/// Starts at 0 and rises, in phase with the sine
+#[derive(Default, Clone)]
+pub struct Triangle;
+impl Wave for Triangle{
+    fn at(p: f64, _width: f64) -> f64{
+        1.0 - 4.0 * ((p + 0.25).fract() - 0.5).abs()
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Square;
+impl Wave for Square{
+    fn at(p: f64, _width: f64) -> f64{
+        if p < 0.5 {1.0} else {-1.0}
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Pulse;
+impl Wave for Pulse{
+    const PULSE: bool = true;
+    fn at(p: f64, width: f64) -> f64{
+        if p < width {1.0} else {-1.0}
+    }
+}
+
+/// Periodic wave, phase is in cycles
+/// ( freq amp phase -- gen ) or ( freq amp phase width -- gen ) for pulses
+#[derive(Default, Clone)]
+pub struct Oscillator<W>{
+    // Position in the cycle, accumulated so the frequency can change
+    pos: f64,
+    wave: PhantomData<W>
+}
+
+impl<W: Wave + Default + Clone + 'static> Generator for Oscillator<W>{
+    fn num_args(&self) -> usize {
+        if W::PULSE {4} else {3}
+    }
+    fn nextf(&mut self, env: &GenEnv) -> f64{
+        let (freq, amp, phase) = (env.arg(0), env.arg(1), env.arg(2));
+        let p = (self.pos + phase).rem_euclid(1.0);
+        self.pos = (self.pos + freq / env.sample_rate).rem_euclid(1.0);
+        amp * W::at(p, env.arg(3))
+    }
+    fn make_clone(&self) -> Box<dyn Generator> {
+        Box::new(self.clone())
+    }
+}
+
+/// Uniform noise from -amp to amp
+/// ( amp -- gen )
+#[derive(Clone)]
+pub struct WhiteNoise{
+    state: u64
+}
+
+impl Default for WhiteNoise{
+    fn default() -> Self{
+        Self{state: 0x9E37_79B9_7F4A_7C15}
+    }
+}
+
+impl WhiteNoise{
+    /// Xorshift, from -1 to 1
+    fn sample(&mut self) -> f64{
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+}
+
+impl Generator for WhiteNoise{
+    fn num_args(&self) -> usize {
+        1
+    }
+    fn nextf(&mut self, env: &GenEnv) -> f64{
+        env.arg(0) * self.sample()
+    }
+    fn make_clone(&self) -> Box<dyn Generator> {
+        Box::new(self.clone())
+    }
+}
+
+/// Noise falling at 3dB per octave, filtered white noise
+/// ( amp -- gen )
+#[derive(Default, Clone)]
+pub struct PinkNoise{
+    white: WhiteNoise,
+    b: [f64; 3]
+}
+
+impl Generator for PinkNoise{
+    fn num_args(&self) -> usize {
+        1
+    }
+    fn nextf(&mut self, env: &GenEnv) -> f64{
+        // Paul Kellet's economy filter, scaled and clipped to stay within amp
+        let w = self.white.sample();
+        self.b[0] = 0.99765 * self.b[0] + w * 0.0990460;
+        self.b[1] = 0.96300 * self.b[1] + w * 0.2965164;
+        self.b[2] = 0.57000 * self.b[2] + w * 1.0526913;
+        let pink = (self.b[0] + self.b[1] + self.b[2] + w * 0.1848) * 0.125;
+        env.arg(0) * pink.clamp(-1.0, 1.0)
+    }
+    fn make_clone(&self) -> Box<dyn Generator> {
+        Box::new(self.clone())
+    }
+}
+/*
+Combinators, built from other generators
+Lengths are fixed when they are built
+*/
+
+/// Finite generator of the first n samples
+#[derive(Clone)]
+pub struct Take{
+    source: GeneratorUnit,
+    n: usize
+}
+
+impl Take{
+    pub fn new(source: GeneratorUnit, n: usize) -> Self{
+        let n = source.remaining().map_or(n, |left| left.min(n));
+        Self{source, n}
+    }
+}
+
+impl Generator for Take{
+    fn num_args(&self) -> usize {
+        0
+    }
+    fn nextf(&mut self, _env: &GenEnv) -> f64{
+        self.source.nextf()
+    }
+    fn make_clone(&self) -> Box<dyn Generator> {
+        Box::new(self.clone())
+    }
+    fn length(&self, _env: &GenEnv) -> Option<usize>{
+        Some(self.n)
+    }
+    fn fill(&mut self, env: &mut GenEnv, block: &mut [f64]){
+        self.source.fill(block);
+        env.counter += block.len();
+    }
+}
+
+/// Source without its first n samples, which are dropped when the first sample is needed
+#[derive(Clone)]
+pub struct Skip{
+    source: GeneratorUnit,
+    skip: usize,
+    n: Option<usize>
+}
+
+impl Skip{
+    pub fn new(source: GeneratorUnit, skip: usize) -> Self{
+        let n = source.remaining().map(|left| left.saturating_sub(skip));
+        Self{source, skip, n}
+    }
+    
+    /// Drop the skipped samples a block at a time
+    fn drop_skipped(&mut self){
+        let mut scratch = [0.0; 512];
+        while self.skip > 0 && !self.source.ended(){
+            let k = self.skip.min(scratch.len());
+            self.source.fill(&mut scratch[..k]);
+            self.skip -= k;
+        }
+        self.skip = 0;
+    }
+}
+
+impl Generator for Skip{
+    fn num_args(&self) -> usize {
+        0
+    }
+    fn nextf(&mut self, _env: &GenEnv) -> f64{
+        self.drop_skipped();
+        self.source.nextf()
+    }
+    fn make_clone(&self) -> Box<dyn Generator> {
+        Box::new(self.clone())
+    }
+    fn length(&self, _env: &GenEnv) -> Option<usize>{
+        self.n
+    }
+    fn fill(&mut self, env: &mut GenEnv, block: &mut [f64]){
+        self.drop_skipped();
+        self.source.fill(block);
+        env.counter += block.len();
+    }
+}
+
+/// Shorter of two lengths, where None is endless
+fn shortest(a: Option<usize>, b: Option<usize>) -> Option<usize>{
+    match (a, b){
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, None) => a,
+        (None, b) => b
+    }
+}
+
+/// Two generators sample by sample with op, until the shorter one ends
+#[derive(Clone)]
+pub struct Combine{
+    a: GeneratorUnit,
+    b: GeneratorUnit,
+    op: FloatOp,
+    n: Option<usize>
+}
+
+impl Combine{
+    pub fn new(a: GeneratorUnit, b: GeneratorUnit, op: FloatOp) -> Self{
+        let n = shortest(a.remaining(), b.remaining());
+        Self{a, b, op, n}
+    }
+}
+
+impl Generator for Combine{
+    fn num_args(&self) -> usize {
+        0
+    }
+    fn nextf(&mut self, _env: &GenEnv) -> f64{
+        (self.op)(&self.a.nextf(), &self.b.nextf())
+    }
+    fn make_clone(&self) -> Box<dyn Generator> {
+        Box::new(self.clone())
+    }
+    fn length(&self, _env: &GenEnv) -> Option<usize>{
+        self.n
+    }
+    fn fill(&mut self, env: &mut GenEnv, block: &mut [f64]){
+        let mut other = vec![0.0; block.len()];
+        self.a.fill(block);
+        self.b.fill(&mut other);
+        for (x, y) in block.iter_mut().zip(&other){
+            *x = (self.op)(x, y);
+        }
+        env.counter += block.len();
+    }
+}
+
+/// Samples of two generators in turn, starting with a
+#[derive(Clone)]
+pub struct Interleave{
+    a: GeneratorUnit,
+    b: GeneratorUnit,
+    n: Option<usize>
+}
+
+impl Interleave{
+    pub fn new(a: GeneratorUnit, b: GeneratorUnit) -> Self{
+        let n = shortest(a.remaining(), b.remaining()).map(|n| 2 * n);
+        Self{a, b, n}
+    }
+}
+
+impl Generator for Interleave{
+    fn num_args(&self) -> usize {
+        0
+    }
+    fn nextf(&mut self, env: &GenEnv) -> f64{
+        if env.counter.is_multiple_of(2) {self.a.nextf()} else {self.b.nextf()}
+    }
+    fn make_clone(&self) -> Box<dyn Generator> {
+        Box::new(self.clone())
+    }
+    fn length(&self, _env: &GenEnv) -> Option<usize>{
+        self.n
+    }
+}
+
+/// Values of a list repeated forever
+#[derive(Clone)]
+pub struct Cycle{
+    values: Rc<[f64]>
+}
+
+impl Cycle{
+    pub fn new(values: &[ForthVal]) -> Result<Self, ForthErr>{
+        if values.is_empty(){
+            return Err(ForthErr::new(ErrKind::Invalid, "can't cycle an empty list"));
         }
-        else{
-            self.value += 1;
+        let values = values.iter().map(|v| v.to_float()).collect::<Result<Rc<[f64]>, ForthErr>>()?;
+        Ok(Self{values})
+    }
+}
+
+impl Generator for Cycle{
+    fn num_args(&self) -> usize {
+        0
+    }
+    fn nextf(&mut self, env: &GenEnv) -> f64{
+        self.values[env.counter % self.values.len()]
+    }
+    fn make_clone(&self) -> Box<dyn Generator> {
+        Box::new(self.clone())
+    }
+}
+
+/// Every nth sample of the source, starting with the first
+#[derive(Clone)]
+pub struct Decimate{
+    source: GeneratorUnit,
+    every: usize,
+    n: Option<usize>
+}
+
+impl Decimate{
+    pub fn new(source: GeneratorUnit, every: usize) -> Result<Self, ForthErr>{
+        if every == 0{
+            return Err(ForthErr::new(ErrKind::Invalid, "can't take every 0th sample"));
         }
-        self.value as f64
+        let n = source.remaining().map(|left| left.div_ceil(every));
+        Ok(Self{source, every, n})
+    }
+}
+
+impl Generator for Decimate{
+    fn num_args(&self) -> usize {
+        0
+    }
+    fn nextf(&mut self, _env: &GenEnv) -> f64{
+        let result = self.source.nextf();
+        for _i in 1..self.every{
+            self.source.nextf();
+        }
+        result
     }
     fn make_clone(&self) -> Box<dyn Generator> {
-        Box::new(Ramp::default())
+        Box::new(self.clone())
+    }
+    fn length(&self, _env: &GenEnv) -> Option<usize>{
+        self.n
+    }
+}
+
+/// Running sum of the source
+#[derive(Clone)]
+pub struct Accumulate{
+    source: GeneratorUnit,
+    sum: f64
+}
+
+impl Accumulate{
+    pub fn new(source: GeneratorUnit) -> Self{
+        Self{source, sum: 0.0}
+    }
+}
+
+impl Generator for Accumulate{
+    fn num_args(&self) -> usize {
+        0
+    }
+    fn nextf(&mut self, _env: &GenEnv) -> f64{
+        self.sum += self.source.nextf();
+        self.sum
+    }
+    fn make_clone(&self) -> Box<dyn Generator> {
+        Box::new(self.clone())
+    }
+    fn length(&self, _env: &GenEnv) -> Option<usize>{
+        self.source.remaining()
+    }
+}
+
+/// Mean of the last n samples of the source, fewer at the start
+#[derive(Clone)]
+pub struct Window{
+    source: GeneratorUnit,
+    size: usize,
+    recent: VecDeque<f64>,
+    n: Option<usize>
+}
+
+impl Window{
+    pub fn new(source: GeneratorUnit, size: usize) -> Result<Self, ForthErr>{
+        if size == 0{
+            return Err(ForthErr::new(ErrKind::Invalid, "can't average over 0 samples"));
+        }
+        let n = source.remaining();
+        Ok(Self{source, size, recent: VecDeque::new(), n})
+    }
+}
+
+impl Generator for Window{
+    fn num_args(&self) -> usize {
+        0
+    }
+    fn nextf(&mut self, _env: &GenEnv) -> f64{
+        if self.recent.len() == self.size{
+            self.recent.pop_front();
+        }
+        self.recent.push_back(self.source.nextf());
+        self.recent.iter().sum::<f64>() / self.recent.len() as f64
+    }
+    fn make_clone(&self) -> Box<dyn Generator> {
+        Box::new(self.clone())
+    }
+    fn length(&self, _env: &GenEnv) -> Option<usize>{
+        self.n
     }
-}
\ No newline at end of file
+}
+
+/// A finite generator followed by another
+#[derive(Clone)]
+pub struct Concat{
+    a: GeneratorUnit,
+    b: GeneratorUnit,
+    n: Option<usize>
+}
+
+impl Concat{
+    pub fn new(a: GeneratorUnit, b: GeneratorUnit) -> Result<Self, ForthErr>{
+        let first = match a.remaining(){
+            Some(n) => n,
+            None => {return Err(ForthErr::new(ErrKind::Invalid, "can't concat after an endless generator"));}
+        };
+        let n = b.remaining().map(|n| n + first);
+        Ok(Self{a, b, n})
+    }
+}
+
+impl Generator for Concat{
+    fn num_args(&self) -> usize {
+        0
+    }
+    fn nextf(&mut self, _env: &GenEnv) -> f64{
+        if self.a.ended() {self.b.nextf()} else {self.a.nextf()}
+    }
+    fn make_clone(&self) -> Box<dyn Generator> {
+        Box::new(self.clone())
+    }
+    fn length(&self, _env: &GenEnv) -> Option<usize>{
+        self.n
+    }
+}
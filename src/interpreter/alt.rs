@@ -3,8 +3,10 @@
 /* This is for defining like prefix method */
 /* defininitions, assembly */
 /*********************************/
-use crate::types::{ForthVal, ForthErr};
+use crate::types::{ErrKind, ForthVal, ForthErr};
 use crate::interpreter::{WorkspaceContext, ForthRoutine};
+use crate::interpreter::control::Compiler;
+use crate::interpreter::vm::assemble;
 
 use crate::proc::Proc;
 
@@ -16,9 +18,9 @@ pub enum AltMode{
 }
 
 #[derive(Clone, Copy)]
-struct AltTrait{
-    pub comments: bool,
+pub(crate) struct AltTrait{
     pub compiled: bool,
+    pub immediate: bool,
     pub consumes_stack: usize,
     pub startmode: DefinitionMode
 }
@@ -29,25 +31,25 @@ pub trait AltMethod{
     /// Returns Ok(CONTINUE) to keep getting values in buffer (for multiple argument)
     /// Returns Ok(DONE) to indicate method is finished reading
     fn consume(&mut self, ws: &WorkspaceContext, tokens: &Vec<ForthVal>, out: &mut Vec<ForthVal>) -> Result<AltMode, ForthErr>{
-        Err(ForthErr::ErrString(format!("Consume not implemented")))
+        Err(ForthErr::new(ErrKind::Invalid, "Consume not implemented"))
     }
     
     /// make changes to workspace
     fn finish(&self, ws: &mut WorkspaceContext, word: &String, built: &Vec<ForthVal>) -> Result<(), ForthErr>{
-        Err(ForthErr::ErrString(format!("Finish unimplemented")))
+        Err(ForthErr::new(ErrKind::Invalid, "Finish unimplemented"))
     }
     
     fn traits(&self) -> AltTrait{
         AltTrait{
-            comments: true,
             compiled: true,
+            immediate: false,
             consumes_stack: 0,
             startmode: DefinitionMode::Define
         }
     }
     
-    fn as_val(&self) -> ForthVal{
-        ForthVal::Err(format!("Can't create as a value"))
+    fn as_val(&self, _built: &Vec<ForthVal>) -> Result<ForthVal, ForthErr>{
+        Err(ForthErr::new(ErrKind::Invalid, "Can't create as a value"))
     }
     
     fn tokenizes(&self) -> bool{
@@ -56,15 +58,11 @@ pub trait AltMethod{
 }
 
 #[derive(Clone, Copy)]
-enum DefinitionMode{
+pub(crate) enum DefinitionMode{
     Define,
-    Comment,
     Compile
 }
 
-const COMMENT_ENTRY: &str = "(";
-const COMMENT_EXIT: &str = ")";
-
 /// Object for doing any prefix operations
 /// Should be able to do most cases
 /// Specific operations are defined by
@@ -75,8 +73,7 @@ pub struct AltCollect{
     buffer: Vec<ForthVal>,
     built: Vec<ForthVal>,
     method: Box<dyn AltMethod>,
-    traits: AltTrait,
-    entry: bool
+    traits: AltTrait
 }
 
 impl AltCollect{
@@ -88,31 +85,20 @@ impl AltCollect{
             buffer: Vec::new(),
             built: Vec::new(),
             method: method,
-            traits: traits,
-            entry: false
+            traits: traits
         }
     }
     
     /// Gather n number of values from the stack
     pub fn consume_stack(&mut self, ws: &mut WorkspaceContext) -> Result<AltMode, ForthErr>{
         for _i in 0..self.traits.consumes_stack{
-            self.buffer.push(ws.pop().unwrap());
+            self.buffer.push(ws.pop()?);
         }
         self.method.consume(ws, &self.buffer, &mut self.built)
     }
     
     /// Gather next value
     pub fn next(&mut self, ws: &mut WorkspaceContext, token: &ForthVal) -> Result<AltMode, ForthErr>{
-        if self.entry{
-            // On first word, check if there is a comment entry
-            self.entry = false;
-            if self.traits.comments && matches(token, COMMENT_ENTRY){
-                // Next things are comments
-                self.mode = DefinitionMode::Comment;
-                return Ok(AltMode::CONTINUE);
-            }
-        }
-        
         match self.mode {
             DefinitionMode::Define => {
                 if let ForthVal::Sym(word) = token{
@@ -133,7 +119,6 @@ impl AltCollect{
                     }
                     else{
                         // Things to compile (i.e. word definition)
-                        self.entry = true;
                         self.mode = DefinitionMode::Compile;
                         return Ok(AltMode::CONTINUE);
                     }
@@ -150,20 +135,22 @@ impl AltCollect{
                 }
                 
             },
-            DefinitionMode::Comment => {
-               if matches(token, COMMENT_EXIT){
-                   self.mode = DefinitionMode::Compile;
-               }
-               // pass
-            }
         }
         Ok(AltMode::CONTINUE)
     }
     
-    pub fn finish(&mut self, ws: &mut WorkspaceContext) -> Result<(), ForthErr>{
+    /// Finish collecting, returns a value to run for immediate methods
+    pub fn finish(&mut self, ws: &mut WorkspaceContext) -> Result<Option<ForthVal>, ForthErr>{
         match &self.word{
-            Some(w) => self.method.finish(ws, w, &self.built),
-            None => Ok(ws.push(self.method.as_val()))
+            Some(w) => self.method.finish(ws, w, &self.built).map(|_| None),
+            None => {
+                let v = self.method.as_val(&self.built)?;
+                if self.traits.immediate{
+                    return Ok(Some(v));
+                }
+                ws.push(v);
+                Ok(None)
+            }
         }
     }
 }
@@ -178,22 +165,10 @@ fn matches(t: &ForthVal, s: &str) -> bool{
     return false;
 }
 
-fn compiled_token(ws: &WorkspaceContext, t: &ForthVal) -> Result<ForthVal, ForthErr>{
-    match t{
-        ForthVal::Sym(s) => {
-            match ws.dictionary.get_id(s){
-                Some(id) => {return Ok(ForthVal::Func(*id))},
-                None => {return Err(ForthErr::ErrString(format!("Word not found {}", s)));}
-            }
-        },
-        _ => {
-            Ok(t.clone())
-        }
-    }
-}
-
 #[derive(Default)]
-pub struct DefineWord{}
+pub struct DefineWord{
+    compiler: Compiler
+}
 
 impl AltMethod for DefineWord{
     fn consume(&mut self, ws: &WorkspaceContext, tokens: &Vec<ForthVal>, out: &mut Vec<ForthVal>) -> Result<AltMode, ForthErr> {
@@ -201,24 +176,65 @@ impl AltMethod for DefineWord{
             if matches(t, ";"){
                 return Ok(AltMode::DONE);
             }
-            match compiled_token(ws, t){
-                Ok(ForthVal::Null) => (),
-                Err(e) => {return Err(e);},
-                Ok(v) => {out.push(v)}
-            }
+            self.compiler.compile(ws, t, out)?;
         }
         Ok(AltMode::NEXT)
     }
     
     fn finish(&self, ws: &mut WorkspaceContext, word: &String, built: &Vec<ForthVal>) -> Result<(), ForthErr> {
         if built.len() == 0{
-            return Err(ForthErr::ErrString(format!("Empty definition: {}", word)));
+            return Err(ForthErr::new(ErrKind::Compile, format!("Empty definition: {}", word)));
         }
-        ws.dictionary.insert_routine(word, ForthRoutine::Compiled(built.clone()));
+        self.compiler.finish()?;
+        ws.dictionary.insert_routine(word, ForthRoutine::Compiled(assemble(built)));
         Ok(())
     }
 }
 
+/// Control structure used outside of a definition
+/// compiled as an anonymous word and run once it is closed
+pub struct Block{
+    compiler: Compiler,
+    opener: Option<String>
+}
+
+impl Block{
+    pub fn new(opener: &str) -> Self{
+        Self{
+            compiler: Compiler::default(),
+            opener: Some(opener.to_string())
+        }
+    }
+}
+
+impl AltMethod for Block{
+    fn consume(&mut self, ws: &WorkspaceContext, tokens: &Vec<ForthVal>, out: &mut Vec<ForthVal>) -> Result<AltMode, ForthErr> {
+        if let Some(opener) = self.opener.take(){
+            self.compiler.compile(ws, &ForthVal::Sym(opener), out)?;
+        }
+        for t in tokens{
+            self.compiler.compile(ws, t, out)?;
+            if !self.compiler.is_open(){
+                return Ok(AltMode::DONE);
+            }
+        }
+        Ok(AltMode::NEXT)
+    }
+    
+    fn traits(&self) -> AltTrait{
+        AltTrait{
+            compiled: true,
+            immediate: true,
+            consumes_stack: 0,
+            startmode: DefinitionMode::Compile
+        }
+    }
+    
+    fn as_val(&self, built: &Vec<ForthVal>) -> Result<ForthVal, ForthErr>{
+        Ok(ForthVal::Callable(ForthRoutine::Compiled(assemble(built))))
+    }
+}
+
 #[derive(Default)]
 pub struct Const{}
 
@@ -230,22 +246,52 @@ impl AltMethod for Const{
     
     fn finish(&self, ws: &mut WorkspaceContext, word: &String, built: &Vec<ForthVal>) -> Result<(), ForthErr> {
         if built.len() == 0{
-            return Err(ForthErr::ErrString(format!("Empty definition: {}", word)));
+            return Err(ForthErr::new(ErrKind::Compile, format!("Empty definition: {}", word)));
         }
-        ws.dictionary.insert_routine(word, ForthRoutine::Compiled(built.clone()));
+        ws.dictionary.insert_routine(word, ForthRoutine::Compiled(assemble(built)));
         Ok(())
     }
     
     fn traits(&self) -> AltTrait {
         AltTrait{
-            comments: false,
             compiled: false,
+            immediate: false,
             consumes_stack: 1,
             startmode: DefinitionMode::Define
         }
     }
 }
 
+/// Push the execution token of the next word
+#[derive(Default)]
+pub struct Tick{}
+
+impl AltMethod for Tick{
+    fn consume(&mut self, _ws: &WorkspaceContext, _tokens: &Vec<ForthVal>, _out: &mut Vec<ForthVal>) -> Result<AltMode, ForthErr> {
+        Ok(AltMode::DONE)
+    }
+    
+    fn finish(&self, ws: &mut WorkspaceContext, word: &String, _built: &Vec<ForthVal>) -> Result<(), ForthErr> {
+        match ws.dictionary.get_id(word){
+            Some(id) => {
+                let id = *id;
+                ws.push(ForthVal::Func(id));
+                Ok(())
+            },
+            None => Err(ForthErr::new(ErrKind::UnknownWord, word.clone()))
+        }
+    }
+    
+    fn traits(&self) -> AltTrait {
+        AltTrait{
+            compiled: false,
+            immediate: false,
+            consumes_stack: 0,
+            startmode: DefinitionMode::Define
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct Var{}
 
@@ -257,7 +303,7 @@ impl AltMethod for Var{
     
     fn finish(&self, ws: &mut WorkspaceContext, word: &String, built: &Vec<ForthVal>) -> Result<(), ForthErr> {
         if built.len() == 0{
-            return Err(ForthErr::ErrString(format!("Empty definition: {}", word)));
+            return Err(ForthErr::new(ErrKind::Compile, format!("Empty definition: {}", word)));
         }
         ws.mem.assign_local(word, &built[0]);
         Ok(())
@@ -265,8 +311,8 @@ impl AltMethod for Var{
     
     fn traits(&self) -> AltTrait {
         AltTrait{
-            comments: false,
             compiled: false,
+            immediate: false,
             consumes_stack: 1,
             startmode: DefinitionMode::Define
         }
@@ -284,18 +330,18 @@ impl AltMethod for ClientVar{
     
     fn finish(&self, ws: &mut WorkspaceContext, word: &String, built: &Vec<ForthVal>) -> Result<(), ForthErr> {
         if built.len() == 0{
-            return Err(ForthErr::ErrString(format!("Empty definition: {}", word)));
+            return Err(ForthErr::new(ErrKind::Compile, format!("Empty definition: {}", word)));
         }
         match ws.mem.assign_client(word, &built[0]){
             Ok(_) => Ok(()),
-            Err(s) => Err(ForthErr::ErrString(s))
+            Err(s) => Err(ForthErr::new(ErrKind::Device, s))
         }
     }
     
     fn traits(&self) -> AltTrait {
         AltTrait{
-            comments: false,
             compiled: false,
+            immediate: false,
             consumes_stack: 1,
             startmode: DefinitionMode::Define
         }
@@ -335,6 +381,8 @@ impl Default for ProcMode{
 pub struct ProcBuilder{
     proc: Proc,
     prop: Option<Property>,
+    // Forms containing the one being built, with their open property
+    parents: Vec<(Proc, Property)>,
     mode: ProcMode
 }
 
@@ -347,23 +395,49 @@ impl ProcBuilder{
         }
         None
     }
+    
+    /// Store the open property in the form
+    fn end_property(&mut self) -> Result<(), ForthErr>{
+        if let Some(prop) = self.prop.take(){
+            if prop.vals.len() != 1{
+                return Err(ForthErr::new(ErrKind::Parse,
+                    format!("Field {} needs one value, got {}", prop.name, prop.vals.len())));
+            }
+            self.proc.set(&prop.name, prop.vals[0].clone());
+        }
+        Ok(())
+    }
 }
 
 impl AltMethod for ProcBuilder{ 
     fn consume(&mut self, _ws: &WorkspaceContext, tokens: &Vec<ForthVal>, out: &mut Vec<ForthVal>) -> Result<AltMode, ForthErr> {
         for t in tokens{
             if matches(t, "}"){
-                return Ok(AltMode::DONE);
+                self.end_property()?;
+                match self.parents.pop(){
+                    Some((parent, mut prop)) => {
+                        // Nested form is the value of the parent property
+                        let inner = std::mem::replace(&mut self.proc, parent);
+                        prop.push(ForthVal::Form(inner));
+                        self.prop = Some(prop);
+                    },
+                    None => {return Ok(AltMode::DONE);}
+                }
             }
             else if let Some(prop) = self.new_property(t){
-                println!("Found property {}", prop);
                 // TODO if special field add program
+                self.end_property()?;
                 self.prop = Some(Property::new(prop));
             }
+            else if matches(t, "{"){
+                match self.prop.take(){
+                    Some(prop) => self.parents.push((std::mem::take(&mut self.proc), prop)),
+                    None => {return Err(ForthErr::new(ErrKind::Parse, "Form prior to property"));}
+                }
+            }
             else{
-                println!("Property contents: {:?}", t);
                 if self.prop.is_none(){
-                    return Err(ForthErr::ErrString(format!("Token prior to property {:?}", t)));
+                    return Err(ForthErr::new(ErrKind::Parse, format!("Token prior to property {:?}", t)));
                 }
                 match self.mode{
                     ProcMode::Prop => {self.prop.as_mut().unwrap().push(t.clone());},
@@ -376,15 +450,15 @@ impl AltMethod for ProcBuilder{
     
     fn traits(&self) -> AltTrait{
         AltTrait{
-            comments: false,
             compiled: true,
+            immediate: false,
             consumes_stack: 0,
             startmode: DefinitionMode::Compile
         }
     }
     
-    fn as_val(&self) -> ForthVal {
-        ForthVal::Form(self.proc.clone())
+    fn as_val(&self, _built: &Vec<ForthVal>) -> Result<ForthVal, ForthErr> {
+        Ok(ForthVal::Form(self.proc.clone()))
     }
 }
 
@@ -398,8 +472,8 @@ struct Asm{
 impl AltMethod for Asm{
     fn traits(&self) -> AltTrait {
         AltTrait{
-            comments: false,
             compiled: true,
+            immediate: false,
             consumes_stack: 0,
             startmode: DefinitionMode::Define
         }
@@ -415,7 +489,7 @@ impl AltMethod for Asm{
                     self.code.push(s.clone())
                 },
                 _ => {
-                    return Err(ForthErr::ErrString(format!("code needs everything to be strings")));
+                    return Err(ForthErr::new(ErrKind::TypeMismatch, "code needs everything to be strings"));
                 }
             }
         }
@@ -0,0 +1,292 @@
+/*********************************/
+/* Control structures ************/
+/* Resolves if/else/then and     */
+/* loops into branches while     */
+/* compiling                     */
+/*********************************/
+use crate::types::{ErrKind, ForthVal, ForthErr, Flow};
+use crate::interpreter::{ForthRoutine, WorkspaceContext};
+use crate::interpreter::vm::assemble;
+use crate::reader::parse_number;
+
+/// Words which open a control structure
+/// When used at top level these compile an anonymous block
+pub const OPENERS: [&str; 5] = ["if", "do", "?do", "begin", "[:"];
+
+/// Unresolved control structure
+enum Control{
+    // index of the conditional jump to patch
+    If(usize),
+    // index of the jump over the else branch
+    Else(usize),
+    // start of loop body, and jumps to the end of the loop
+    Do(usize, Vec<usize>),
+    // start of indefinite loop
+    Begin(usize),
+    // start of indefinite loop, and the conditional exit to patch
+    While(usize, usize),
+    // start of quotation body
+    Quote(usize)
+}
+
+/// Runtime state of a counted loop
+#[derive(Clone, Debug)]
+pub struct LoopFrame{
+    pub index: i64,
+    pub limit: i64
+}
+
+impl LoopFrame{
+    /// Increment loop index, returns true when the loop is finished
+    /// Loop finishes when crossing the boundary between limit-1 and limit
+    pub fn step(&mut self, n: i64) -> bool{
+        let before = self.index.wrapping_sub(self.limit);
+        self.index = self.index.wrapping_add(n);
+        let after = self.index.wrapping_sub(self.limit);
+        (before ^ after) < 0
+    }
+}
+
+/// Compiles tokens into a program
+/// keeping track of open control structures
+#[derive(Default)]
+pub struct Compiler{
+    control: Vec<Control>,
+    // Tick word waiting for the name of a word
+    tick: Option<String>
+}
+
+/// Convert a token to its compiled form
+fn compiled_token(ws: &WorkspaceContext, t: &ForthVal) -> Result<ForthVal, ForthErr>{
+    match t{
+        ForthVal::Sym(s) => {
+            if let Some(id) = ws.dictionary.get_id(s){
+                return Ok(ForthVal::Func(*id));
+            }
+            // Variables are looked up when run
+            if ws.mem.get(s).is_some(){
+                return Ok(t.clone());
+            }
+            // Numbers in a base with letters as digits
+            match parse_number(s, ws.base)?{
+                Some(v) => Ok(v),
+                None => Err(ForthErr::new(ErrKind::UnknownWord, s.clone()))
+            }
+        },
+        _ => {
+            Ok(t.clone())
+        }
+    }
+}
+
+/// Move a jump from position start in a program to the start of a new one
+fn relocate(v: ForthVal, start: usize) -> ForthVal{
+    match v{
+        ForthVal::Flow(Flow::Jump(t)) => ForthVal::Flow(Flow::Jump(t - start)),
+        ForthVal::Flow(Flow::JumpIfZero(t)) => ForthVal::Flow(Flow::JumpIfZero(t - start)),
+        ForthVal::Flow(Flow::QDo(t)) => ForthVal::Flow(Flow::QDo(t - start)),
+        ForthVal::Flow(Flow::Loop(t)) => ForthVal::Flow(Flow::Loop(t - start)),
+        ForthVal::Flow(Flow::PlusLoop(t)) => ForthVal::Flow(Flow::PlusLoop(t - start)),
+        ForthVal::Flow(Flow::Leave(t)) => ForthVal::Flow(Flow::Leave(t - start)),
+        _ => v
+    }
+}
+
+/// Point an unresolved jump at target
+fn patch(out: &mut [ForthVal], at: usize, target: usize){
+    out[at] = match out[at]{
+        ForthVal::Flow(Flow::Jump(_)) => ForthVal::Flow(Flow::Jump(target)),
+        ForthVal::Flow(Flow::JumpIfZero(_)) => ForthVal::Flow(Flow::JumpIfZero(target)),
+        ForthVal::Flow(Flow::QDo(_)) => ForthVal::Flow(Flow::QDo(target)),
+        ForthVal::Flow(Flow::Leave(_)) => ForthVal::Flow(Flow::Leave(target)),
+        _ => unreachable!("Patching non jump")
+    };
+}
+
+impl Compiler{
+    /// Check if there are unfinished control structures
+    pub fn is_open(&self) -> bool{
+        !self.control.is_empty() || self.tick.is_some()
+    }
+
+    /// Check if a quotation is being compiled
+    fn in_quotation(&self) -> bool{
+        self.control.iter().any(|c| matches!(c, Control::Quote(_)))
+    }
+
+    /// Compile a single token
+    pub fn compile(&mut self, ws: &WorkspaceContext, t: &ForthVal, out: &mut Vec<ForthVal>) -> Result<(), ForthErr>{
+        if let Some(tick) = self.tick.take(){
+            return match t{
+                ForthVal::Sym(s) => match ws.dictionary.get_id(s){
+                    Some(id) => {
+                        out.push(ForthVal::Flow(Flow::Tick(*id)));
+                        Ok(())
+                    },
+                    None => Err(ForthErr::new(ErrKind::UnknownWord, s.clone()))
+                },
+                _ => Err(ForthErr::new(ErrKind::Compile, format!("{} needs a word name", tick)))
+            };
+        }
+        if let ForthVal::Sym(s) = t{
+            if self.control_word(s, out)?{
+                return Ok(());
+            }
+        }
+        match compiled_token(ws, t)?{
+            ForthVal::Null => (),
+            v => out.push(v)
+        };
+        Ok(())
+    }
+
+    /// Compile control words, returns false if s is not a control word
+    fn control_word(&mut self, s: &str, out: &mut Vec<ForthVal>) -> Result<bool, ForthErr>{
+        match s{
+            "if" => {
+                self.control.push(Control::If(out.len()));
+                out.push(ForthVal::Flow(Flow::JumpIfZero(0)));
+            },
+            "else" => {
+                match self.control.pop(){
+                    Some(Control::If(at)) => {
+                        self.control.push(Control::Else(out.len()));
+                        out.push(ForthVal::Flow(Flow::Jump(0)));
+                        let end = out.len();
+                        patch(out, at, end);
+                    },
+                    _ => {return Err(ForthErr::new(ErrKind::Compile, "else without matching if"));}
+                }
+            },
+            "then" => {
+                match self.control.pop(){
+                    Some(Control::If(at)) | Some(Control::Else(at)) => {
+                        let end = out.len();
+                        patch(out, at, end);
+                    },
+                    _ => {return Err(ForthErr::new(ErrKind::Compile, "then without matching if"));}
+                }
+            },
+            "do" => {
+                out.push(ForthVal::Flow(Flow::Do));
+                self.control.push(Control::Do(out.len(), Vec::new()));
+            },
+            "?do" => {
+                // skip is resolved along with leave
+                self.control.push(Control::Do(out.len()+1, vec![out.len()]));
+                out.push(ForthVal::Flow(Flow::QDo(0)));
+            },
+            "loop" | "+loop" => {
+                match self.control.pop(){
+                    Some(Control::Do(body, leaves)) => {
+                        out.push(ForthVal::Flow(match s{
+                            "loop" => Flow::Loop(body),
+                            _ => Flow::PlusLoop(body)
+                        }));
+                        let end = out.len();
+                        for at in leaves{
+                            patch(out, at, end);
+                        }
+                    },
+                    _ => {return Err(ForthErr::new(ErrKind::Compile, format!("{} without matching do", s)));}
+                }
+            },
+            "begin" => {
+                self.control.push(Control::Begin(out.len()));
+            },
+            "until" | "again" => {
+                match self.control.pop(){
+                    Some(Control::Begin(dest)) => {
+                        out.push(ForthVal::Flow(match s{
+                            "until" => Flow::JumpIfZero(dest),
+                            _ => Flow::Jump(dest)
+                        }));
+                    },
+                    _ => {return Err(ForthErr::new(ErrKind::Compile, format!("{} without matching begin", s)));}
+                }
+            },
+            "while" => {
+                match self.control.pop(){
+                    Some(Control::Begin(dest)) => {
+                        self.control.push(Control::While(dest, out.len()));
+                        out.push(ForthVal::Flow(Flow::JumpIfZero(0)));
+                    },
+                    _ => {return Err(ForthErr::new(ErrKind::Compile, "while without matching begin"));}
+                }
+            },
+            // Without an open while, repeat is the list word
+            "repeat" => {
+                match self.control.last(){
+                    Some(&Control::While(dest, at)) => {
+                        self.control.pop();
+                        out.push(ForthVal::Flow(Flow::Jump(dest)));
+                        let end = out.len();
+                        patch(out, at, end);
+                    },
+                    _ => {return Ok(false);}
+                }
+            },
+            // Quotations are separate programs, so these can't reach the word around them
+            "exit" | "recurse" if self.in_quotation() => {
+                return Err(ForthErr::new(ErrKind::Compile, format!("{} inside a quotation", s)));
+            },
+            "exit" => {
+                out.push(ForthVal::Flow(Flow::Exit));
+            },
+            "recurse" => {
+                out.push(ForthVal::Flow(Flow::Recurse));
+            },
+            "'" | "[']" => {
+                self.tick = Some(s.to_string());
+            },
+            "[:" => {
+                self.control.push(Control::Quote(out.len()));
+            },
+            ";]" => {
+                match self.control.pop(){
+                    Some(Control::Quote(start)) => {
+                        // Body is compiled in place then moved into its own program
+                        let body: Vec<ForthVal> = out.split_off(start).into_iter()
+                            .map(|v| relocate(v, start))
+                            .collect();
+                        out.push(ForthVal::Callable(ForthRoutine::Compiled(assemble(&body))));
+                    },
+                    Some(_) => {return Err(ForthErr::new(ErrKind::Compile, "Unterminated control structure in quotation"));},
+                    None => {return Err(ForthErr::new(ErrKind::Compile, ";] without matching [:"));}
+                }
+            },
+            "leave" | "break" => {
+                // Leave the innermost loop, can be inside other structures
+                // but not a quotation, which is compiled on its own
+                let leaves = self.control.iter_mut().rev()
+                    .take_while(|c| !matches!(c, Control::Quote(_)))
+                    .find_map(|c|{
+                        match c{
+                            Control::Do(_, leaves) => Some(leaves),
+                            _ => None
+                        }
+                    });
+                match leaves{
+                    Some(leaves) => {
+                        leaves.push(out.len());
+                        out.push(ForthVal::Flow(Flow::Leave(0)));
+                    },
+                    None => {return Err(ForthErr::new(ErrKind::Compile, format!("{} outside of loop", s)));}
+                }
+            },
+            _ => {return Ok(false);}
+        };
+        Ok(true)
+    }
+
+    /// Check the compiled program is complete
+    pub fn finish(&self) -> Result<(), ForthErr>{
+        if let Some(tick) = &self.tick{
+            return Err(ForthErr::new(ErrKind::Compile, format!("{} needs a word name", tick)));
+        }
+        if self.is_open(){
+            return Err(ForthErr::new(ErrKind::Compile, "Unterminated control structure"));
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,16 @@
 use std::{collections::HashMap, rc::Rc};
 
-use super::{generator, ForthFn, ForthFnGen, ForthRoutine, Generator};
+use super::{generator, ForthExec, ForthFn, ForthFnGen, ForthRoutine, Generator};
 
 use crate::types::ForthVal;
-use crate::interpreter::alt::{AltMethod, AltCollect};
+use crate::interpreter::alt::{AltMethod, AltCollect, Block};
 
 #[derive(Clone)]
 pub struct Dictionary{
     // Main library
     lookup: HashMap<String, usize>,
-    library: HashMap<usize, ForthRoutine>,
+    library: Vec<ForthRoutine>,
+    names: Vec<String>,
 
     local_lookup: HashMap<String, usize>,
     
@@ -20,7 +21,8 @@ impl Dictionary{
     pub fn new() -> Self{
         Self{
             lookup: HashMap::new(),
-            library: HashMap::new(),
+            library: Vec::new(),
+            names: Vec::new(),
             
             local_lookup: HashMap::new(),
             
@@ -47,6 +49,11 @@ impl Dictionary{
         self.insert_routine(&s.to_string(), ForthRoutine::Prim(f))
     }
     
+    /// Insert builtin with access to the interpreter
+    pub fn insert_exec(&mut self, s: &str, f: ForthExec) -> usize{
+        self.insert_routine(&s.to_string(), ForthRoutine::Exec(f))
+    }
+    
     /// Insert generator object
     pub fn insert_generator<T: Generator + Default + 'static>(&mut self, s: &str) -> usize{
         self.insert_routine(&s.to_string(), ForthRoutine::Prim(Rc::new(|ws| generator::<T>(ws))))
@@ -58,11 +65,24 @@ impl Dictionary{
             s,
             |ws|{
                 ws.set_alt(AltCollect::new(Box::new(Method::default())));
-                ForthVal::Null
+                Ok(ForthVal::Null)
             }
         );
     }
     
+    /// Insert control structure
+    /// Outside of a definition the structure is compiled and run once closed
+    pub fn insert_control(&mut self, s: &str){
+        let opener = s.to_string();
+        self.insert_ptr(
+            s,
+            Rc::new(move |ws|{
+                ws.set_alt(AltCollect::new(Box::new(Block::new(&opener))));
+                Ok(ForthVal::Null)
+            })
+        );
+    }
+    
     /// Insert routine
     pub fn insert_routine(&mut self, s: &String, f: ForthRoutine) -> usize{
         
@@ -77,15 +97,16 @@ impl Dictionary{
         };
         
         lookup.insert(s.clone(), id);
-        library.insert(id, f);
+        if id == library.len(){
+            library.push(f);
+            self.names.push(s.clone());
+        }
+        else{
+            library[id] = f;
+        }
         id
     }
     
-    /// Special control lookup
-    pub fn then_id(&self) -> usize{
-        *self.get_id("then").unwrap()
-    }
-    
     /// Get byte code for a function string
     pub fn get_id(&self, s: &str) -> Option<&usize>{
         if self.local{
@@ -96,18 +117,16 @@ impl Dictionary{
         self.lookup.get(s)
     }
     
-    /// Get function from string name
-    pub fn get_fn(&self, s: &str) -> Option<&ForthRoutine>{
-        if let Some(id) = self.get_id(s){
-            self.get_fn_from_id(id)
-        }
-        else{
-            None
+    /// Get name of a function from its id code
+    pub fn get_name(&self, v: &usize) -> &str{
+        match self.names.get(*v){
+            Some(name) => name.as_str(),
+            None => "unnamed"
         }
     }
     
     /// Get function from id code
     pub fn get_fn_from_id(&self, v: &usize) -> Option<&ForthRoutine>{
-        self.library.get(v)
+        self.library.get(*v)
     }
 }
\ No newline at end of file
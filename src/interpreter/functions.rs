@@ -1,36 +1,60 @@
-use std::{rc::Rc, thread, time::Duration};
+use std::{cmp::Ordering, rc::Rc, thread, time::Duration};
+use num_bigint::BigInt;
+use num_traits::{ToPrimitive, Zero};
 
-use crate::{drivers::Serial, interpreter::WorkspaceContext, types::{ForthErr, ForthRet, ForthVal, AsmPromise}};
+use crate::{drivers::Serial, interpreter::WorkspaceContext, types::{ErrKind, ForthErr, ForthRet, ForthVal, AsmPromise, IntOp, FloatOp}};
 use crate::interpreter::alt::*;
+use crate::interpreter::control::OPENERS;
 use crate::drivers::DeviceInterface;
 use crate::interpreter::mem::Location;
 
 use crate::visual::decode;
 
-use super::{math, Dictionary, ForthRoutine, GenEnv, Generator, GeneratorUnit, Mode, Natural, Workspace};
+use super::{math, Dictionary, ForthFnGen, Generator, GeneratorUnit, Mode, Workspace};
+use crate::generator::{Natural, Oscillator, PinkNoise, Pulse, Ramp, Saw, Sine, Square, Triangle, WhiteNoise};
+use crate::generator::{Accumulate, Combine, Concat, Cycle, Decimate, Interleave, Skip, Take, Window};
 
 /// Duplicate top of stack
-pub fn dup(ws: &mut WorkspaceContext) -> ForthVal{
-    let t = ws.last().unwrap();
-    ws.push(t.clone());
-    ForthVal::Null
-}
-
-pub fn generator<T: Generator + Default + 'static>(ws: &mut WorkspaceContext) -> ForthVal{
-    let mut gu = GeneratorUnit{
-        env: GenEnv::default(),
-        gen: Box::new(T::default()),
-        trace: Vec::new(),
-        ws: Workspace::new()
-    };
-    gu.consume(ws);
-    ForthVal::Generator(gu)
+pub fn dup(ws: &mut WorkspaceContext) -> ForthRet{
+    match ws.last(){
+        Some(t) => Ok(t.clone()),
+        None => Err(ForthErr::underflow())
+    }
+}
+
+/// Pop n values from the stack, deepest value first
+/// Stack is left untouched if there are not enough values
+fn pop_n(ws: &mut WorkspaceContext, n: usize) -> Result<Vec<ForthVal>, ForthErr>{
+    if ws.len() < n{
+        return Err(ForthErr::underflow());
+    }
+    let mut values = Vec::new();
+    for _i in 0..n{
+        values.push(ws.pop()?);
+    }
+    values.reverse();
+    Ok(values)
+}
+
+/// Pop a non negative index
+fn pop_index(ws: &mut WorkspaceContext) -> Result<usize, ForthErr>{
+    let v = ws.pop_int()?;
+    if v < 0{
+        return Err(ForthErr::new(ErrKind::Index, format!("Negative index {}", v)));
+    }
+    Ok(v as usize)
+}
+
+pub fn generator<T: Generator + Default + 'static>(ws: &mut WorkspaceContext) -> ForthRet{
+    let mut gu = GeneratorUnit::new(Box::new(T::default()));
+    gu.consume(ws)?;
+    Ok(ForthVal::Generator(gu))
 }
 
 pub fn to_int(v: &ForthVal) -> ForthRet{
     match v{
         ForthVal::Float(f) => Ok(ForthVal::Int(f.round() as i64)),
-        ForthVal::Int(v) => Ok(ForthVal::Int(*v)),
+        ForthVal::Big(_) => Ok(ForthVal::Int(v.to_int()?)),
         ForthVal::List(values) => {
             let mut result = Vec::new();
             for va in values{
@@ -38,18 +62,8 @@ pub fn to_int(v: &ForthVal) -> ForthRet{
             }
             Ok(ForthVal::List(result))
         },
-        ForthVal::Generator(g) => {
-            // girls when they write sensible code voice
-            let mut gp = g.clone();
-    
-          gp.push(&ForthVal::Callable(ForthRoutine::Prim(Rc::new(|ws|{
-              to_int(&ws.pop().unwrap()).unwrap()
-          }))));
-          Ok(ForthVal::Generator(gp))
-        },
-        _ => Err(ForthErr::ErrString(
-            format!("Invalid type to int {:?}", v))
-        )
+        // Generators give floats, so samples are only rounded
+        _ => v.operate_unary(Some(|a| Ok(*a)), |f| f.round())
     }
 }
 
@@ -71,42 +85,230 @@ fn unwrap(ws: &mut WorkspaceContext, v: &ForthVal) -> Vec<ForthVal>{
 /// Define print functions
 fn setup_print(dict: &mut Dictionary){
     dict.insert(".", |ws|{
-        if let Some(v) = ws.pop(){
+        let v = ws.pop()?;
+        if ws.base == 10{
             ws.reply.push(v);
         }
         else{
-            ws.reply.push(ForthVal::Str("Stack empty".to_string()));
+            ws.write_text(&format!("{} ", v.to_string_radix(ws.base)))?;
+        }
+        Ok(ForthVal::Null)
+    });
+    
+    // Number base for input and output
+    dict.insert("hex", |ws|{
+        ws.base = 16;
+        Ok(ForthVal::Null)
+    });
+    
+    dict.insert("decimal", |ws|{
+        ws.base = 10;
+        Ok(ForthVal::Null)
+    });
+    
+    dict.insert("binary", |ws|{
+        ws.base = 2;
+        Ok(ForthVal::Null)
+    });
+    
+    dict.insert("base", |ws|{
+        Ok(ForthVal::Int(ws.base as i64))
+    });
+    
+    dict.insert("set_base", |ws|{
+        let base = ws.pop_int()?;
+        if !(2..=36).contains(&base){
+            return Err(ForthErr::new(ErrKind::Invalid, format!("base {} not in 2 to 36", base)));
         }
-        ForthVal::Null
+        ws.base = base as u32;
+        Ok(ForthVal::Null)
     });
     
     dict.insert(".s", |ws|{
         for i in 0..ws.len(){
             ws.reply.push(ws.peek(i).unwrap().clone());
         }
-        ForthVal::Null
+        Ok(ForthVal::Null)
     });
     
+    // Words are shown as the hardware sees them in 32 bit mode
     dict.insert(".x", |ws|{
-        if let Some(v) = ws.pop(){
-            ws.reply.push(ForthVal::Str(format!("{:#02x}", 
-                v.to_int().unwrap())));
-        }
-        else{
-            ws.reply.push(ForthVal::Str("Stack empty".to_string()));
+        let v = ws.pop()?;
+        if let ForthVal::Big(v) = v{
+            ws.reply.push(ForthVal::Str(format!("{:#x}", v)));
+            return Ok(ForthVal::Null);
         }
-        ForthVal::Null
+        let v = v.to_int()?;
+        let v = if ws.wrap32 {v as u32 as i64} else {v};
+        ws.reply.push(ForthVal::Str(format!("{:#02x}", v)));
+        Ok(ForthVal::Null)
     });
     
     dict.insert(".b", |ws|{
-        if let Some(v) = ws.pop(){
-            ws.reply.push(ForthVal::Str(format!("{:#02b}", 
-                v.to_int().unwrap())));
+        let v = ws.pop_int()?;
+        let v = if ws.wrap32 {v as u32 as i64} else {v};
+        ws.reply.push(ForthVal::Str(format!("{:#02b}", v)));
+        Ok(ForthVal::Null)
+    });
+    
+    // Text output, strings are read before the word
+    dict.insert(".\"", |ws|{
+        let v = ws.pop()?;
+        match v{
+            ForthVal::Str(s) => {ws.write_text(&s)?; Ok(ForthVal::Null)},
+            _ => Err(ForthErr::mismatch("string", &v))
         }
-        else{
-            ws.reply.push(ForthVal::Str("Stack empty".to_string()));
+    });
+    dict.insert("s\"", |_ws|{
+        Ok(ForthVal::Null)
+    });
+    
+    // ( str -- ) write a string, list of character codes or bytes read as UTF-8
+    dict.insert("type", |ws|{
+        let v = ws.pop()?;
+        match v{
+            ForthVal::Str(s) => ws.write_text(&s)?,
+            ForthVal::Bytes(b) => ws.write_text(&String::from_utf8_lossy(&b))?,
+            ForthVal::List(values) => {
+                let mut text = String::new();
+                for c in values{
+                    text.push(to_char(c.to_int()?)?);
+                }
+                ws.write_text(&text)?;
+            },
+            _ => {return Err(ForthErr::mismatch("string", &v));}
+        };
+        Ok(ForthVal::Null)
+    });
+    
+    // ( n -- ) write character with code n
+    dict.insert("emit", |ws|{
+        let c = to_char(ws.pop_int()?)?;
+        ws.write_text(c.encode_utf8(&mut [0; 4]))?;
+        Ok(ForthVal::Null)
+    });
+    dict.insert("cr", |ws|{
+        ws.write_text("\n")?;
+        Ok(ForthVal::Null)
+    });
+    dict.insert("space", |ws|{
+        ws.write_text(" ")?;
+        Ok(ForthVal::Null)
+    });
+}
+
+/// Character from its code
+fn to_char(v: i64) -> Result<char, ForthErr>{
+    u32::try_from(v).ok()
+        .and_then(char::from_u32)
+        .ok_or_else(|| ForthErr::new(ErrKind::Invalid, format!("Invalid character {}", v)))
+}
+
+/// Define stack operations
+fn setup_stack(dict: &mut Dictionary){
+    dict.insert("dup", dup);
+    
+    dict.insert("drop", |ws|{
+        ws.pop()?;
+        Ok(ForthVal::Null)
+    });
+    
+    // ( a b -- b a )
+    dict.insert("swap", |ws|{
+        let v = pop_n(ws, 2)?;
+        Ok(ForthVal::Vector(vec![v[1].clone(), v[0].clone()]))
+    });
+    
+    // ( a b -- a b a )
+    dict.insert("over", |ws|{
+        let v = pop_n(ws, 2)?;
+        Ok(ForthVal::Vector(vec![v[0].clone(), v[1].clone(), v[0].clone()]))
+    });
+    
+    // ( a b c -- b c a )
+    dict.insert("rot", |ws|{
+        let v = pop_n(ws, 3)?;
+        Ok(ForthVal::Vector(vec![v[1].clone(), v[2].clone(), v[0].clone()]))
+    });
+    
+    // ( a b c -- c a b )
+    dict.insert("-rot", |ws|{
+        let v = pop_n(ws, 3)?;
+        Ok(ForthVal::Vector(vec![v[2].clone(), v[0].clone(), v[1].clone()]))
+    });
+    
+    // Older name for -rot
+    dict.insert("abc_cab", |ws|{
+        let v = pop_n(ws, 3)?;
+        Ok(ForthVal::Vector(vec![v[2].clone(), v[0].clone(), v[1].clone()]))
+    });
+    
+    // ( a b -- b )
+    dict.insert("nip", |ws|{
+        let v = pop_n(ws, 2)?;
+        Ok(v[1].clone())
+    });
+    
+    // ( a b -- b a b )
+    dict.insert("tuck", |ws|{
+        let v = pop_n(ws, 2)?;
+        Ok(ForthVal::Vector(vec![v[1].clone(), v[0].clone(), v[1].clone()]))
+    });
+    
+    // ( xu ... x0 u -- xu ... x0 xu )
+    dict.insert("pick", |ws|{
+        let u = pop_index(ws)?;
+        if u >= ws.len(){
+            return Err(ForthErr::underflow());
+        }
+        Ok(ws.peek(ws.len() - 1 - u).unwrap().clone())
+    });
+    
+    // ( xu xu-1 ... x0 u -- xu-1 ... x0 xu )
+    dict.insert("roll", |ws|{
+        let u = pop_index(ws)?;
+        let mut v = pop_n(ws, u+1)?;
+        let x = v.remove(0);
+        v.push(x);
+        Ok(ForthVal::Vector(v))
+    });
+    
+    dict.insert("depth", |ws|{
+        Ok(ForthVal::Int(ws.len() as i64))
+    });
+    
+    // ( a b -- a b a b )
+    dict.insert("2dup", |ws|{
+        let v = pop_n(ws, 2)?;
+        Ok(ForthVal::Vector(vec![v[0].clone(), v[1].clone(), v[0].clone(), v[1].clone()]))
+    });
+    
+    dict.insert("2drop", |ws|{
+        pop_n(ws, 2)?;
+        Ok(ForthVal::Null)
+    });
+    
+    // ( a b c d -- c d a b )
+    dict.insert("2swap", |ws|{
+        let v = pop_n(ws, 4)?;
+        Ok(ForthVal::Vector(vec![v[2].clone(), v[3].clone(), v[0].clone(), v[1].clone()]))
+    });
+    
+    // ( a b c d -- a b c d a b )
+    dict.insert("2over", |ws|{
+        let mut v = pop_n(ws, 4)?;
+        v.push(v[0].clone());
+        v.push(v[1].clone());
+        Ok(ForthVal::Vector(v))
+    });
+    
+    // Duplicate if non zero
+    dict.insert("?dup", |ws|{
+        match ws.last(){
+            Some(v) if is_true(v)? => Ok(v.clone()),
+            Some(_) => Ok(ForthVal::Null),
+            None => Err(ForthErr::underflow())
         }
-        ForthVal::Null
     });
 }
 
@@ -116,6 +318,719 @@ fn setup_alt(dict: &mut Dictionary){
     dict.insert_alt_mode::<ProcBuilder>("{");
     dict.insert_alt_mode::<Var>("=");
     dict.insert_alt_mode::<ClientVar>("#=");
+    dict.insert_alt_mode::<Tick>("'");
+    dict.insert_alt_mode::<Tick>("[']");
+}
+
+/// Define control structures
+fn setup_control(dict: &mut Dictionary){
+    for opener in OPENERS{
+        dict.insert_control(opener);
+    }
+    
+    // Only valid inside a control structure
+    dict.insert("else", |_ws|{
+        Err(ForthErr::new(ErrKind::Compile, "else without matching if"))
+    });
+    dict.insert("then", |_ws|{
+        Err(ForthErr::new(ErrKind::Compile, "then without matching if"))
+    });
+    dict.insert("loop", |_ws|{
+        Err(ForthErr::new(ErrKind::Compile, "loop without matching do"))
+    });
+    dict.insert("+loop", |_ws|{
+        Err(ForthErr::new(ErrKind::Compile, "+loop without matching do"))
+    });
+    dict.insert("leave", |_ws|{
+        Err(ForthErr::new(ErrKind::Compile, "leave outside of loop"))
+    });
+    dict.insert("break", |_ws|{
+        Err(ForthErr::new(ErrKind::Compile, "break outside of loop"))
+    });
+    dict.insert("until", |_ws|{
+        Err(ForthErr::new(ErrKind::Compile, "until without matching begin"))
+    });
+    dict.insert("again", |_ws|{
+        Err(ForthErr::new(ErrKind::Compile, "again without matching begin"))
+    });
+    dict.insert("while", |_ws|{
+        Err(ForthErr::new(ErrKind::Compile, "while without matching begin"))
+    });
+    dict.insert(";]", |_ws|{
+        Err(ForthErr::new(ErrKind::Compile, ";] without matching [:"))
+    });
+    dict.insert("exit", |_ws|{
+        Err(ForthErr::new(ErrKind::Compile, "exit outside of definition"))
+    });
+    dict.insert("recurse", |_ws|{
+        Err(ForthErr::new(ErrKind::Compile, "recurse outside of definition"))
+    });
+    
+    // ( xt -- ) run an execution token
+    dict.insert_exec("execute", |w|{
+        let xt = w.ctx.pop()?;
+        w.execute(&xt)
+    });
+    
+    // ( n -- ) limit on nested words
+    dict.insert("set_max_depth", |ws|{
+        ws.max_depth = pop_index(ws)?;
+        Ok(ForthVal::Null)
+    });
+    
+    // Loop indices
+    dict.insert("i", |ws|{
+        match ws.loops.last(){
+            Some(frame) => Ok(ForthVal::Int(frame.index)),
+            None => Err(ForthErr::new(ErrKind::Compile, "i used outside of loop"))
+        }
+    });
+    dict.insert("j", |ws|{
+        match ws.loops.len(){
+            n if n >= 2 => Ok(ForthVal::Int(ws.loops[n-2].index)),
+            _ => Err(ForthErr::new(ErrKind::Compile, "j used outside of nested loop"))
+        }
+    });
+}
+
+/// Raising and catching errors
+fn setup_exceptions(dict: &mut Dictionary){
+    // ( n -- ) raise n unless it is 0
+    dict.insert("throw", |ws|{
+        match ws.pop_int()?{
+            0 => Ok(ForthVal::Null),
+            n => Err(ForthErr::new(ErrKind::Throw(n), format!("code {}", n)))
+        }
+    });
+    dict.insert("abort", |_ws|{
+        Err(ForthErr::new(ErrKind::Throw(-1), ""))
+    });
+    // Message is read as a string before the word
+    dict.insert("abort\"", |ws|{
+        let msg = ws.pop()?;
+        match msg{
+            ForthVal::Str(s) => Err(ForthErr::new(ErrKind::Throw(-2), s)),
+            _ => Err(ForthErr::mismatch("string", &msg))
+        }
+    });
+    
+    // ( xt -- code ) run xt, on error the stacks are restored and the code pushed
+    dict.insert_exec("catch", |w|{
+        let xt = w.ctx.pop()?;
+        let stack = w.ctx.stack.clone();
+        let rdepth = w.ctx.rstack.len();
+        let loops = w.ctx.loops.len();
+        let marks = w.ctx.marks.len();
+        match w.execute(&xt){
+            Ok(()) => w.ctx.push(ForthVal::Int(0)),
+            Err(e) => {
+                w.ctx.stack = stack;
+                w.ctx.rstack.truncate(rdepth)?;
+                w.ctx.loops.truncate(loops);
+                w.ctx.marks.truncate(marks);
+                w.ctx.push(ForthVal::Int(e.kind.code()));
+            }
+        }
+        Ok(())
+    });
+}
+
+/// Pop a list
+fn pop_list(ws: &mut WorkspaceContext) -> Result<Vec<ForthVal>, ForthErr>{
+    match ws.pop()?{
+        ForthVal::List(values) => Ok(values),
+        v => Err(ForthErr::mismatch("list", &v))
+    }
+}
+
+/// Flag for true or false, true is all bits set
+fn flag(b: bool) -> ForthVal{
+    ForthVal::Int(if b {-1} else {0})
+}
+
+/// Check if a flag is true, any non zero number is true
+fn is_true(v: &ForthVal) -> Result<bool, ForthErr>{
+    match v{
+        ForthVal::Int(n) => Ok(*n != 0),
+        ForthVal::Float(f) => Ok(*f != 0.0),
+        _ => Err(ForthErr::mismatch("flag", v))
+    }
+}
+
+/// Compare b with a, a list and a single value are compared element by element
+/// Two lists are ordered as a whole
+fn ordered(b: &ForthVal, a: &ForthVal, test: fn(Ordering) -> bool) -> ForthRet{
+    match (b, a){
+        (ForthVal::List(_), ForthVal::List(_)) => Ok(flag(test(b.compare(a)?))),
+        (ForthVal::List(values), _) => {
+            Ok(ForthVal::List(values.iter().map(|v| ordered(v, a, test)).collect::<Result<_, _>>()?))
+        },
+        (_, ForthVal::List(values)) => {
+            Ok(ForthVal::List(values.iter().map(|v| ordered(b, v, test)).collect::<Result<_, _>>()?))
+        },
+        _ => Ok(flag(test(b.compare(a)?)))
+    }
+}
+
+/// Compare the top two values in order, giving a flag
+/// Generators are compared sample by sample into a generator of flags
+fn comparison(test: fn(Ordering) -> bool, fi: IntOp, ff: FloatOp) -> ForthFnGen{
+    let per_sample = math::binary_op(fi, ff);
+    Rc::new(move |ws|{
+        if ws.len() < 2{
+            return Err(ForthErr::underflow());
+        }
+        let generator = |v: Option<&ForthVal>| matches!(v, Some(ForthVal::Generator(_)));
+        if generator(ws.peek(ws.len() - 1)) || generator(ws.peek(ws.len() - 2)){
+            return per_sample(ws);
+        }
+        let a = ws.pop()?;
+        let b = ws.pop()?;
+        ordered(&ws.wrap(b), &ws.wrap(a), test)
+    })
+}
+
+/// Compare ints as unsigned, giving a flag
+fn unsigned_comparison(ws: &mut WorkspaceContext, test: fn(Ordering) -> bool) -> ForthRet{
+    let a = ws.pop_int()?;
+    let b = ws.pop_int()?;
+    let order = match ws.wrap32{
+        true => (b as u32).cmp(&(a as u32)),
+        false => (b as u64).cmp(&(a as u64))
+    };
+    Ok(flag(test(order)))
+}
+
+/// Convert ints in a value with f
+fn convert_ints(v: &ForthVal, f: fn(i64) -> i64) -> ForthRet{
+    match v{
+        ForthVal::Int(n) => Ok(ForthVal::Int(f(*n))),
+        ForthVal::List(values) => {
+            let mut result = Vec::with_capacity(values.len());
+            for v in values{
+                result.push(convert_ints(v, f)?);
+            }
+            Ok(ForthVal::List(result))
+        },
+        _ => Err(ForthErr::mismatch("int or list", v))
+    }
+}
+
+/// Mask of the low n bits
+fn low_mask(n: u32) -> u64{
+    if n >= 64 {u64::MAX} else {(1 << n) - 1}
+}
+
+/// Pop a bit range ( hi lo -- ), as width and lowest bit
+fn pop_bit_range(ws: &mut WorkspaceContext) -> Result<(u32, u32), ForthErr>{
+    let lo = ws.pop_int()?;
+    let hi = ws.pop_int()?;
+    if lo < 0 || hi < lo || hi > 63{
+        return Err(ForthErr::new(ErrKind::Invalid, format!("bad bit range {}..{}", hi, lo)));
+    }
+    Ok(((hi - lo + 1) as u32, lo as u32))
+}
+
+/// Shift amount for big ints
+fn big_shift(n: &BigInt) -> Result<usize, ForthErr>{
+    n.to_usize().ok_or_else(|| ForthErr::new(ErrKind::Range, format!("bad shift amount {}", n)))
+}
+
+/// Bitwise operations and bit fields
+fn setup_bits(dict: &mut Dictionary){
+    // Floats are truncated for bitwise operations
+    // Shift counts must be less than the width, rshift is logical and arshift arithmetic
+    // Big ints have no width, so rshift is not defined on them
+    dict.insert_ptr("lshift", math::shift_op(
+            |a, b|{Ok(b.wrapping_shl(math::shift_count(*a, 32)?))},
+            |a, b|{Ok(b.wrapping_shl(math::shift_count(*a, 64)?))},
+            |a, b|{(*b as i64).checked_shl(*a as u32).map_or(f64::NAN, |v| v as f64)},
+            Some(|a, b|{Ok(b << big_shift(a)?)})));
+    
+    dict.insert_ptr("rshift", math::shift_op(
+            |a, b|{Ok(((*b as u32) >> math::shift_count(*a, 32)?) as i64)},
+            |a, b|{Ok(((*b as u64) >> math::shift_count(*a, 64)?) as i64)},
+            |a, b|{(*b as i64 as u64).checked_shr(*a as u32).map_or(f64::NAN, |v| v as f64)},
+            None));
+    
+    dict.insert_ptr("arshift", math::shift_op(
+            |a, b|{Ok((*b as i32 >> math::shift_count(*a, 32)?) as i64)},
+            |a, b|{Ok(b >> math::shift_count(*a, 64)?)},
+            |a, b|{(*b as i64).checked_shr(*a as u32).map_or(f64::NAN, |v| v as f64)},
+            Some(|a, b|{Ok(b >> big_shift(a)?)})));
+    
+    dict.insert_ptr("&", math::big_op(
+            |a, b|{Ok(b&a)},
+            |a, b|{((*b as i64) & (*a as i64)) as f64},
+            Some(|a, b|{Ok(b&a)})));
+    
+    dict.insert_ptr("|", math::big_op(
+            |a, b|{Ok(b|a)},
+            |a, b|{((*b as i64) | (*a as i64)) as f64},
+            Some(|a, b|{Ok(b|a)})));
+    
+    dict.insert_ptr("xor", math::big_op(
+            |a, b|{Ok(b^a)},
+            |a, b|{((*b as i64) ^ (*a as i64)) as f64},
+            Some(|a, b|{Ok(b^a)})));
+    
+    dict.insert_ptr("invert", math::unary_op(Some(|a| Ok(!a)), |a| !(*a as i64) as f64));
+    
+    // Counts the low 32 bits in 32 bit mode
+    let count32 = math::unary_op(Some(|a| Ok((*a as u32).count_ones() as i64)), |a| (*a as i64 as u32).count_ones() as f64);
+    let count64 = math::unary_op(Some(|a| Ok(a.count_ones() as i64)), |a| (*a as i64).count_ones() as f64);
+    dict.insert_ptr("popcount", Rc::new(move |ws|{
+        if ws.wrap32{
+            count32(ws)
+        }
+        else{
+            count64(ws)
+        }
+    }));
+    
+    // ( value n -- value ) sign extend from the low n bits
+    dict.insert_ptr("sign_extend", math::binary_op(
+            |a, b|{
+                let shift = 64 - (*a).clamp(1, 64) as u32;
+                Ok((b << shift) >> shift)
+            },
+            |a, b|{
+                let shift = 64 - (*a as i64).clamp(1, 64) as u32;
+                (((*b as i64) << shift) >> shift) as f64
+            }));
+    
+    // ( value hi lo -- field ) bits hi to lo of value, inclusive
+    dict.insert("bits", |ws|{
+        let (width, lo) = pop_bit_range(ws)?;
+        let value = ws.pop_int()? as u64;
+        Ok(ForthVal::Int(((value >> lo) & low_mask(width)) as i64))
+    });
+    
+    // ( value field hi lo -- value ) replace bits hi to lo of value with field
+    dict.insert("set_bits", |ws|{
+        let (width, lo) = pop_bit_range(ws)?;
+        let field = ws.pop_int()? as u64;
+        let value = ws.pop_int()? as u64;
+        let mask = low_mask(width) << lo;
+        let result = ForthVal::Int(((value & !mask) | ((field << lo) & mask)) as i64);
+        Ok(ws.wrap(result))
+    });
+}
+
+/// Pop ( gen n ), both are checked first so a bad one leaves the stack as it was
+/// n must be at least min
+fn pop_generator_count(ws: &mut WorkspaceContext, min: usize) -> Result<(GeneratorUnit, usize), ForthErr>{
+    if ws.len() < 2{
+        return Err(ForthErr::underflow());
+    }
+    let n = ws.last().unwrap().to_int()?;
+    if n < 0{
+        return Err(ForthErr::new(ErrKind::Index, format!("Negative index {}", n)));
+    }
+    if (n as usize) < min{
+        return Err(ForthErr::new(ErrKind::Invalid, format!("count {} is less than {}", n, min)));
+    }
+    match ws.peek(ws.len() - 2){
+        Some(ForthVal::Generator(_)) => (),
+        Some(v) => {return Err(ForthErr::mismatch("generator", v));},
+        None => {return Err(ForthErr::underflow());}
+    }
+    let n = pop_index(ws)?;
+    Ok((pop_generator(ws)?, n))
+}
+
+fn pop_generator(ws: &mut WorkspaceContext) -> Result<GeneratorUnit, ForthErr>{
+    match ws.pop()?{
+        ForthVal::Generator(gen) => Ok(gen),
+        v => Err(ForthErr::mismatch("generator", &v))
+    }
+}
+
+fn new_generator(gen: impl Generator + 'static) -> ForthVal{
+    ForthVal::Generator(GeneratorUnit::new(Box::new(gen)))
+}
+
+/// Generators built from other generators
+fn setup_generators(dict: &mut Dictionary){
+    // ( gen n -- gen ) first n samples
+    dict.insert("take", |ws|{
+        let (gen, n) = pop_generator_count(ws, 0)?;
+        Ok(new_generator(Take::new(gen, n)))
+    });
+    
+    // ( gen n -- gen ) drop the first n samples
+    dict.insert("skip", |ws|{
+        let (gen, n) = pop_generator_count(ws, 0)?;
+        Ok(new_generator(Skip::new(gen, n)))
+    });
+    
+    // ( gen gen -- gen ) sum of two generators
+    dict.insert("mix", |ws|{
+        let b = pop_generator(ws)?;
+        let a = pop_generator(ws)?;
+        Ok(new_generator(Combine::new(a, b, |a, b| a + b)))
+    });
+    
+    // ( list -- gen ) repeat the values of a list
+    dict.insert("cycle", |ws|{
+        Ok(new_generator(Cycle::new(&pop_list(ws)?)?))
+    });
+    
+    // ( gen n -- gen ) every nth sample
+    dict.insert("decimate", |ws|{
+        let (gen, n) = pop_generator_count(ws, 1)?;
+        Ok(new_generator(Decimate::new(gen, n)?))
+    });
+    
+    // ( gen -- gen ) running sum
+    dict.insert("accumulate", |ws|{
+        Ok(new_generator(Accumulate::new(pop_generator(ws)?)))
+    });
+    
+    // ( gen n -- gen ) mean of the last n samples
+    dict.insert("window", |ws|{
+        let (gen, n) = pop_generator_count(ws, 1)?;
+        Ok(new_generator(Window::new(gen, n)?))
+    });
+    
+    // ( gen -- list ) for finite generators, ( n gen -- list ) for endless ones
+    dict.insert("collect", |ws|{
+        let endless = match ws.last(){
+            Some(ForthVal::Generator(gen)) => gen.remaining().is_none(),
+            Some(v) => {return Err(ForthErr::mismatch("generator", v));},
+            None => {return Err(ForthErr::underflow());}
+        };
+        // The count under an endless generator is checked before either is popped
+        if endless{
+            match ws.len().checked_sub(2).and_then(|i| ws.peek(i)){
+                Some(v) if v.to_int()? >= 0 => (),
+                Some(v) => {return Err(ForthErr::new(ErrKind::Index, format!("Negative index {}", v.to_int()?)));},
+                None => {return Err(ForthErr::underflow());}
+            }
+        }
+        let mut gen = pop_generator(ws)?;
+        let n = match gen.remaining(){
+            Some(n) => n,
+            None => pop_index(ws)?
+        };
+        Ok(ForthVal::List((0..n).map(|_| gen.next()).collect()))
+    });
+}
+
+/// Math on numbers, lists and generators
+fn setup_math(dict: &mut Dictionary){
+    // Without an int operation ints give floats
+    dict.insert_ptr("abs", math::unary_op(Some(|a| a.checked_abs().ok_or_else(ForthErr::overflow)), |a| a.abs()));
+    dict.insert_ptr("negate", math::unary_op(Some(|a| a.checked_neg().ok_or_else(ForthErr::overflow)), |a| -a));
+    dict.insert_ptr("floor", math::unary_op(Some(|a| Ok(*a)), |a| a.floor()));
+    dict.insert_ptr("ceil", math::unary_op(Some(|a| Ok(*a)), |a| a.ceil()));
+    dict.insert_ptr("sqrt", math::unary_op(None, |a| a.sqrt()));
+    dict.insert_ptr("sin", math::unary_op(None, |a| a.sin()));
+    dict.insert_ptr("cos", math::unary_op(None, |a| a.cos()));
+    dict.insert_ptr("tan", math::unary_op(None, |a| a.tan()));
+    dict.insert_ptr("exp", math::unary_op(None, |a| a.exp()));
+    dict.insert_ptr("log", math::unary_op(None, |a| a.ln()));
+    
+    dict.insert_ptr("min", math::binary_op(|a, b| Ok(*a.min(b)), |a, b| a.min(*b)));
+    dict.insert_ptr("max", math::binary_op(|a, b| Ok(*a.max(b)), |a, b| a.max(*b)));
+    // Int powers stay ints, negative exponents truncate toward zero
+    dict.insert_ptr("pow", math::binary_op(
+            |a, b|{
+                if *a < 0{
+                    Ok((*b as f64).powi(*a as i32) as i64)
+                }
+                else{
+                    u32::try_from(*a).ok()
+                        .and_then(|n| b.checked_pow(n))
+                        .ok_or_else(ForthErr::overflow)
+                }
+            },
+            |a, b| b.powf(*a)));
+    
+    // ( x lo hi -- x ) as lo max hi min
+    let min = math::binary_op(|a, b| Ok(*a.min(b)), |a, b| a.min(*b));
+    let max = math::binary_op(|a, b| Ok(*a.max(b)), |a, b| a.max(*b));
+    dict.insert_ptr("clamp", Rc::new(move |ws|{
+        if ws.len() < 3{
+            return Err(ForthErr::underflow());
+        }
+        let hi = ws.pop()?;
+        let lower = max(ws)?;
+        ws.push(lower);
+        ws.push(hi);
+        min(ws)
+    }));
+}
+
+/// Integer widths
+fn setup_widths(dict: &mut Dictionary){
+    // Arithmetic, shifts and comparisons wrap to 32 bits like RV32
+    dict.insert("wrap32", |ws|{
+        ws.wrap32 = true;
+        Ok(ForthVal::Null)
+    });
+    dict.insert("wrap64", |ws|{
+        ws.wrap32 = false;
+        Ok(ForthVal::Null)
+    });
+    
+    // Keep the low bits, sign extended or not
+    fn width(dict: &mut Dictionary, name: &str, f: fn(i64) -> i64){
+        dict.insert_ptr(name, Rc::new(move |ws|{
+            convert_ints(&ws.pop()?, f)
+        }));
+    }
+    width(dict, "to_i8", |v| v as i8 as i64);
+    width(dict, "to_u8", |v| v as u8 as i64);
+    width(dict, "to_i16", |v| v as i16 as i64);
+    width(dict, "to_u16", |v| v as u16 as i64);
+    width(dict, "to_i32", |v| v as i32 as i64);
+    width(dict, "to_u32", |v| v as u32 as i64);
+}
+
+/// Comparisons and logic, flags are -1 for true and 0 for false
+fn setup_logic(dict: &mut Dictionary){
+    // Equality is structural, values of different types are not equal
+    dict.insert("==", |ws|{
+        let a = ws.pop()?;
+        let b = ws.pop()?;
+        Ok(flag(ws.wrap(b).equals_within(&ws.wrap(a), ws.tolerance)))
+    });
+    dict.insert("!=", |ws|{
+        let a = ws.pop()?;
+        let b = ws.pop()?;
+        Ok(flag(!ws.wrap(b).equals_within(&ws.wrap(a), ws.tolerance)))
+    });
+    
+    // ( f -- ) floats closer than this are equal
+    dict.insert("set_tolerance", |ws|{
+        let tolerance = ws.pop()?.to_float()?;
+        if tolerance.is_nan() || tolerance < 0.0{
+            return Err(ForthErr::new(ErrKind::Invalid, format!("tolerance {} is negative", tolerance)));
+        }
+        ws.tolerance = tolerance;
+        Ok(ForthVal::Null)
+    });
+    
+    dict.insert_ptr("<", comparison(|o| o.is_lt(),
+        |a, b|{Ok(-((b < a) as i64))}, |a, b|{-((b < a) as i64) as f64}));
+    dict.insert_ptr(">", comparison(|o| o.is_gt(),
+        |a, b|{Ok(-((b > a) as i64))}, |a, b|{-((b > a) as i64) as f64}));
+    dict.insert_ptr("<=", comparison(|o| o.is_le(),
+        |a, b|{Ok(-((b <= a) as i64))}, |a, b|{-((b <= a) as i64) as f64}));
+    dict.insert_ptr(">=", comparison(|o| o.is_ge(),
+        |a, b|{Ok(-((b >= a) as i64))}, |a, b|{-((b >= a) as i64) as f64}));
+    
+    dict.insert("u<", |ws| unsigned_comparison(ws, |o| o.is_lt()));
+    dict.insert("u>", |ws| unsigned_comparison(ws, |o| o.is_gt()));
+    
+    dict.insert("0=", |ws|{
+        Ok(flag(!is_true(&ws.pop()?)?))
+    });
+    dict.insert("not", |ws|{
+        Ok(flag(!is_true(&ws.pop()?)?))
+    });
+    
+    // Bitwise on flags, so -1 and 0 work as booleans
+    dict.insert("and", |ws|{
+        let a = ws.pop_int()?;
+        let b = ws.pop_int()?;
+        Ok(ForthVal::Int(b & a))
+    });
+    dict.insert("or", |ws|{
+        let a = ws.pop_int()?;
+        let b = ws.pop_int()?;
+        Ok(ForthVal::Int(b | a))
+    });
+}
+
+/// Run xt with args pushed, it must leave a single result
+fn apply(w: &mut Workspace, xt: &ForthVal, args: &[ForthVal]) -> ForthRet{
+    let depth = w.ctx.len();
+    for v in args{
+        w.ctx.push(v.clone());
+    }
+    w.execute(xt)?;
+    if w.ctx.len() != depth + 1{
+        return Err(ForthErr::new(ErrKind::Invalid,
+            format!("expected {} to leave 1 value, got {}", xt.to_string(), w.ctx.len() as i64 - depth as i64)));
+    }
+    w.ctx.pop()
+}
+
+/// Sort values, by keys if given
+fn sort_values(values: Vec<ForthVal>, keys: Option<Vec<ForthVal>>) -> Result<Vec<ForthVal>, ForthErr>{
+    let keys = keys.unwrap_or_else(|| values.clone());
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    let mut err = None;
+    order.sort_by(|a, b|{
+        keys[*a].compare(&keys[*b]).unwrap_or_else(|e|{
+            err.get_or_insert(e);
+            std::cmp::Ordering::Equal
+        })
+    });
+    match err{
+        Some(e) => Err(e),
+        None => Ok(order.into_iter().map(|i| values[i].clone()).collect())
+    }
+}
+
+/// List words, callbacks are execution tokens
+fn setup_lists(dict: &mut Dictionary){
+    // ( list xt -- list ) xt is ( v -- v )
+    dict.insert_exec("map", |w|{
+        let xt = w.ctx.pop()?;
+        let values = pop_list(&mut w.ctx)?;
+        let mut result = Vec::with_capacity(values.len());
+        for v in values{
+            result.push(apply(w, &xt, &[v])?);
+        }
+        w.ctx.push(ForthVal::List(result));
+        Ok(())
+    });
+    
+    // ( list xt -- ) xt is ( v -- )
+    dict.insert_exec("each", |w|{
+        let xt = w.ctx.pop()?;
+        for v in pop_list(&mut w.ctx)?{
+            w.ctx.push(v);
+            w.execute(&xt)?;
+        }
+        Ok(())
+    });
+    
+    // ( list xt -- list ) xt is ( v -- flag )
+    dict.insert_exec("filter", |w|{
+        let xt = w.ctx.pop()?;
+        let mut result = Vec::new();
+        for v in pop_list(&mut w.ctx)?{
+            if is_true(&apply(w, &xt, std::slice::from_ref(&v))?)?{
+                result.push(v);
+            }
+        }
+        w.ctx.push(ForthVal::List(result));
+        Ok(())
+    });
+    
+    // ( list xt -- v ) xt is ( acc v -- acc ), starting from the first value
+    dict.insert_exec("reduce", |w|{
+        let xt = w.ctx.pop()?;
+        let mut values = pop_list(&mut w.ctx)?.into_iter();
+        let mut acc = values.next()
+            .ok_or_else(|| ForthErr::new(ErrKind::Invalid, "reduce of empty list"))?;
+        for v in values{
+            acc = apply(w, &xt, &[acc, v])?;
+        }
+        w.ctx.push(acc);
+        Ok(())
+    });
+    
+    // ( list init xt -- v ) xt is ( acc v -- acc )
+    dict.insert_exec("fold", |w|{
+        let xt = w.ctx.pop()?;
+        let mut acc = w.ctx.pop()?;
+        for v in pop_list(&mut w.ctx)?{
+            acc = apply(w, &xt, &[acc, v])?;
+        }
+        w.ctx.push(acc);
+        Ok(())
+    });
+    
+    // ( list xt -- v flag ) first value where xt is true, or 0 0
+    dict.insert_exec("find", |w|{
+        let xt = w.ctx.pop()?;
+        for v in pop_list(&mut w.ctx)?{
+            if is_true(&apply(w, &xt, std::slice::from_ref(&v))?)?{
+                w.ctx.push(v);
+                w.ctx.push(flag(true));
+                return Ok(());
+            }
+        }
+        w.ctx.push(ForthVal::Int(0));
+        w.ctx.push(flag(false));
+        Ok(())
+    });
+    
+    // ( list xt -- list ) sorted by the keys from xt ( v -- key )
+    dict.insert_exec("sort_by", |w|{
+        let xt = w.ctx.pop()?;
+        let values = pop_list(&mut w.ctx)?;
+        let mut keys = Vec::with_capacity(values.len());
+        for v in values.iter(){
+            keys.push(apply(w, &xt, std::slice::from_ref(v))?);
+        }
+        w.ctx.push(ForthVal::List(sort_values(values, Some(keys))?));
+        Ok(())
+    });
+    
+    // ( list -- list ) numbers, strings or lists in increasing order
+    dict.insert("sort", |ws|{
+        let values = pop_list(ws)?;
+        Ok(ForthVal::List(sort_values(values, None)?))
+    });
+    
+    // ( list v -- n ) position of v, or -1
+    dict.insert("index_of", |ws|{
+        let v = ws.pop()?;
+        let values = pop_list(ws)?;
+        match values.iter().position(|x| x.equals_within(&v, ws.tolerance)){
+            Some(i) => Ok(ForthVal::Int(i as i64)),
+            None => Ok(ForthVal::Int(-1))
+        }
+    });
+    
+    // ( a b -- list ) pairs of values, as long as the shorter list
+    // Generators are interleaved
+    dict.insert("zip", |ws|{
+        if let Some(ForthVal::Generator(_)) = ws.last(){
+            let b = pop_generator(ws)?;
+            let a = pop_generator(ws)?;
+            return Ok(new_generator(Interleave::new(a, b)));
+        }
+        let b = pop_list(ws)?;
+        let a = pop_list(ws)?;
+        Ok(ForthVal::List(a.into_iter().zip(b)
+            .map(|(x, y)| ForthVal::List(vec![x, y]))
+            .collect()))
+    });
+    
+    // ( start end -- list ) integers from start up to end
+    dict.insert("range", |ws|{
+        let end = ws.pop_int()?;
+        let start = ws.pop_int()?;
+        Ok(ForthVal::List((start..end).map(ForthVal::Int).collect()))
+    });
+    
+    // ( list start end -- list ) values from start up to end
+    dict.insert("slice", |ws|{
+        let end = pop_index(ws)?;
+        let start = pop_index(ws)?;
+        let values = pop_list(ws)?;
+        if start > end || end > values.len(){
+            return Err(ForthErr::new(ErrKind::Index,
+                format!("Slice {}..{} out of range for list of length {}", start, end, values.len())));
+        }
+        Ok(ForthVal::List(values[start..end].to_vec()))
+    });
+    
+    // ( a b -- list )
+    dict.insert("concat", |ws|{
+        if let Some(ForthVal::Generator(_)) = ws.last(){
+            let b = pop_generator(ws)?;
+            let a = pop_generator(ws)?;
+            return Ok(new_generator(Concat::new(a, b)?));
+        }
+        let mut b = pop_list(ws)?;
+        let mut a = pop_list(ws)?;
+        a.append(&mut b);
+        Ok(ForthVal::List(a))
+    });
+    
+    dict.insert("reverse", |ws|{
+        let mut values = pop_list(ws)?;
+        values.reverse();
+        Ok(ForthVal::List(values))
+    });
 }
 
 impl Workspace{
@@ -124,49 +1039,68 @@ impl Workspace{
         let dict = &mut self.ctx.dictionary;
         
         setup_print(dict);
+        setup_stack(dict);
         setup_alt(dict);
-    
-        // Stack operations
-        dict.insert(
-            "dup",
-            dup
-        );
+        setup_control(dict);
+        setup_exceptions(dict);
+        setup_lists(dict);
+        setup_logic(dict);
+        setup_widths(dict);
+        setup_math(dict);
+        setup_bits(dict);
+        setup_generators(dict);
         
-        dict.insert(
-            "swap",
-            |ws|{
-                let a = ws.pop().unwrap();
-                let b = ws.pop().unwrap();
-                ForthVal::Vector(vec![a, b])
+        // Return stack
+        dict.insert(">r", |ws|{
+            let v = ws.pop()?;
+            ws.rstack.push(v);
+            Ok(ForthVal::Null)
+        });
+        
+        dict.insert("r>", |ws|{
+            match ws.rstack.pop(){
+                Some(v) => Ok(v),
+                None => Err(ForthErr::new(ErrKind::ReturnStack, "Return stack empty"))
             }
-        );
+        });
         
-        dict.insert(
-            "abc_cab",
-            |ws|{
-                let a = ws.pop().unwrap();
-                let b = ws.pop().unwrap();
-                let c = ws.pop().unwrap();
-                ForthVal::Vector(vec![a, c, b])
+        dict.insert("r@", |ws|{
+            match ws.rstack.last(){
+                Some(v) => Ok(v.clone()),
+                None => Err(ForthErr::new(ErrKind::ReturnStack, "Return stack empty"))
             }
-        );
-                
+        });
+        
+        // Copy top of stack to return stack
+        dict.insert("r!", |ws|{
+            match ws.last(){
+                Some(v) => {ws.rstack.push(v.clone()); Ok(ForthVal::Null)},
+                None => Err(ForthErr::underflow())
+            }
+        });
+        
+        dict.insert("rdrop", |ws|{
+            match ws.rstack.pop(){
+                Some(_v) => Ok(ForthVal::Null),
+                None => Err(ForthErr::new(ErrKind::ReturnStack, "Return stack empty"))
+            }
+        });
+        
         dict.insert(
             "library_set",
             |ws|{
-                let mode = ws.pop().unwrap();
-                ws.dictionary.set_context(mode.to_int().unwrap() != 0);
-                ForthVal::Null
+                let mode = ws.pop_int()?;
+                ws.dictionary.set_context(mode != 0);
+                Ok(ForthVal::Null)
             }
         );
         
         dict.insert(
             "stack_set",
             |ws|{
-                let mode = ws.pop().unwrap();
-                let m = mode.to_int().unwrap() == 0;
+                let m = ws.pop_int()? == 0;
                 ws.stack.local = m;
-                ForthVal::Null
+                Ok(ForthVal::Null)
             }
         );
         
@@ -174,30 +1108,24 @@ impl Workspace{
         dict.insert(
             "stall",
             |ws|{
-                let checking = true;
-                let mut counter = 0;
                 let timeout = 10;
-                let addr = ws.pop().unwrap().to_int().unwrap();
-                while checking{
+                let addr = ws.pop_int()?;
+                for _counter in 0..timeout{
                     let result = ws.serial.read(addr as u32);
                     if let Ok(r) = result{
                         if r == 0{
-                            return ForthVal::Null;
-                        } 
-                    }
-                    counter += 1;
-                    if counter >= timeout{
-                        return ForthVal::Err(format!("Timed out while stalling for state change"));
+                            return Ok(ForthVal::Null);
+                        }
                     }
                 }
-                ForthVal::Null
+                Err(ForthErr::new(ErrKind::Device, "Timed out while stalling for state change"))
             }
         );
         
         dict.insert(
             "stack_size",
             |ws|{
-                ForthVal::Int(ws.stack.len() as i64)
+                Ok(ForthVal::Int(ws.stack.len() as i64))
             }
         );
         
@@ -205,143 +1133,120 @@ impl Workspace{
             "clear",
             |ws| {
                 ws.stack.clear();
-                ForthVal::Null
+                Ok(ForthVal::Null)
             }
         );
         
-        dict.insert("if", |ws|{
-            if ws.pop().unwrap().to_int().unwrap() == 0{
-                ws.mode = Mode::CONDITION;
-            } 
-            ForthVal::Null
-        });
-        
-        dict.insert("then", |ws|{
-            if ws.mode == Mode::CONDITION{
-                ws.mode = Mode::NORMAL;
-            }
-            ForthVal::Null
-        });
-        
         dict.insert(
             "needs",
-            |ws|{ws.mode = Mode::NEEDS; ForthVal::Null}
+            |ws|{ws.mode = Mode::NEEDS; Ok(ForthVal::Null)}
         );
         
         dict.insert(
             "delay",
             |ws| {
-                thread::sleep(Duration::from_millis(ws.pop().unwrap().to_int().unwrap() as u64));
-                ForthVal::Null
-            }
-        );
-        
-        dict.insert(
-            "==",
-            |ws| {
-                let a = ws.pop().unwrap();
-                let b = ws.pop().unwrap();
-                
-                if a.to_int().unwrap() == b.to_int().unwrap(){
-                    ForthVal::Int(1)
-                }
-                else{
-                    ForthVal::Int(0)
-                }
+                let ms = pop_index(ws)?;
+                thread::sleep(Duration::from_millis(ms as u64));
+                Ok(ForthVal::Null)
             }
         );
         
         dict.insert(
             "assert",
             |ws|{
-                let msg = ws.pop().unwrap();
-                let a = ws.pop().unwrap();
-                if a.to_int().unwrap() > 0{
-                    ForthVal::Null
+                let msg = ws.pop()?;
+                let a = ws.pop()?;
+                if is_true(&a)?{
+                    Ok(ForthVal::Null)
                 }
                 else{
-                    ForthVal::Err(msg.to_string())
+                    Err(ForthErr::new(ErrKind::Assert, msg.to_string()))
                 }
             }
         );
         
         dict.insert("play", |ws|{
-            let result = ws.pop().unwrap();
+            let result = ws.pop()?;
            match result{
                ForthVal::Generator(gen) => {
-                   ForthVal::Int(ws.audio.push(&gen) as i64)
+                   Ok(ForthVal::Int(ws.audio.push(&gen) as i64))
                },
                _ => {
-                   ForthVal::Err(format!("Audio channel must be generator {:?}", result))
+                   Err(ForthErr::mismatch("generator", &result))
                }
-           } 
+           }
         });
         
         // Binary operations
         // TODO surely there is some easy way to compress these
-        dict.insert_ptr("+", math::binary_op(|a, b|{b+a}, |a, b|{b+a}));
-        dict.insert_ptr("-", math::binary_op(|a, b|{b-a}, |a, b|{b-a}));
-        dict.insert_ptr("*", math::binary_op(|a, b|{b*a}, |a, b|{b*a}));
-        dict.insert_ptr("/", math::binary_op(|a, b|{b/a}, |a, b|{b/a}));
+        // Ints are checked, big ints grow as needed
+        dict.insert_ptr("+", math::big_op(
+                |a, b|{b.checked_add(*a).ok_or_else(ForthErr::overflow)},
+                |a, b|{b+a},
+                Some(|a, b|{Ok(b+a)})));
+        dict.insert_ptr("-", math::big_op(
+                |a, b|{b.checked_sub(*a).ok_or_else(ForthErr::overflow)},
+                |a, b|{b-a},
+                Some(|a, b|{Ok(b-a)})));
+        dict.insert_ptr("*", math::big_op(
+                |a, b|{b.checked_mul(*a).ok_or_else(ForthErr::overflow)},
+                |a, b|{b*a},
+                Some(|a, b|{Ok(b*a)})));
+        dict.insert_ptr("/", math::big_op(
+                |a, b|{if *a == 0 {Err(ForthErr::divide_by_zero())} else {b.checked_div(*a).ok_or_else(ForthErr::overflow)}},
+                |a, b|{b/a},
+                Some(|a, b|{if a.is_zero() {Err(ForthErr::divide_by_zero())} else {Ok(b/a)}})));
         
-        dict.insert_ptr("&", math::binary_op(|a, b|{b&a}, |_a, _b|{panic!("cant do bitwise on float")}));
+        dict.insert_ptr("%", math::big_op(
+                |a, b|{if *a == 0 {Err(ForthErr::divide_by_zero())} else {b.checked_rem(*a).ok_or_else(ForthErr::overflow)}},
+                |a, b|{b%a},
+                Some(|a, b|{if a.is_zero() {Err(ForthErr::divide_by_zero())} else {Ok(b%a)}})));
         
-        dict.insert_ptr(">", math::binary_op(|a, b|{if b>a{1} else {0}}, |a, b|{if b>a{1.0} else {0.0}}));
-        dict.insert_ptr("<", math::binary_op(|a, b|{if b<a{1} else {0}}, |a, b|{if b<a{1.0} else {0.0}}));
-        dict.insert_ptr("%", math::binary_op(|a, b|{b%a}, |a, b|{b%a}));
+        // Big ints are opt in, with to_big or an n suffix as in 1n
+        dict.insert("to_big", |ws|{
+            Ok(ForthVal::Big(ws.pop()?.to_big()?))
+        });
         
         dict.insert("tofloat", |ws|{
-           ForthVal::Float(ws.pop().unwrap().to_float().unwrap()) 
+           Ok(ForthVal::Float(ws.pop()?.to_float()?))
         });
         
-        dict.insert_ptr("lshift", math::binary_op(
-                |a, b|{b<<a}, 
-                |_a, _b|{panic!("Can't rshift floats")}));
-        
-        dict.insert_ptr("rshift", math::binary_op(
-                |a, b|{b>>a}, 
-                |_a, _b|{panic!("Can't rshift floats")}));
-                
-        dict.insert_ptr("&", math::binary_op(
-                |a, b|{b&a}, 
-                |_a, _b|{panic!("Can't rshift floats")}));
-                
-        dict.insert_ptr("|", math::binary_op(
-                |a, b|{b|a}, 
-                |_a, _b|{panic!("Can't rshift floats")}));
-        
         dict.insert("access", |ws|{
-           let id = ws.pop().unwrap().to_int().unwrap();
-           let v = ws.pop().unwrap();
+           let id = pop_index(ws)?;
+           let v = ws.pop()?;
            match v{
                ForthVal::List(collection) => {
-                   collection[id as usize].clone()
+                   match collection.get(id){
+                       Some(v) => Ok(v.clone()),
+                       None => Err(ForthErr::new(ErrKind::Index,
+                           format!("Index {} out of range for list of length {}", id, collection.len())))
+                   }
                },
                _ => {
-                   ForthVal::Err(format!("Invalid value for collect: {:?}", v))
+                   Err(ForthErr::mismatch("list", &v))
                }
-           } 
+           }
         });
         
         // Serial stuff
         // may make a more unified interface
         // But want to get it off the ground
-        dict.insert("serial_list", 
+        dict.insert("serial_list",
             |_ctx|
                 {Serial::print_ports()});
         
         dict.insert("serial_start",
             |ctx| {
-                let port = &ctx.pop().unwrap();
-                let baud = &ctx.pop().unwrap();
+                let port = &ctx.pop()?;
+                let baud = &ctx.pop()?;
                 ctx.serial.start(port, baud)});
-                
+        
         dict.insert("puts",
             |ctx|{
-                let msg = &ctx.pop().unwrap();
+                let msg = &ctx.pop()?;
                 ctx.serial.put(&msg)
             });
-            
+        
         dict.insert("gets",
             |ctx|{
                 ctx.serial.get()
@@ -349,201 +1254,211 @@ impl Workspace{
         
         dict.insert("list_to_char",
             |ctx|{
-                let msg = &ctx.pop().unwrap();
+                let msg = &ctx.pop()?;
                 let mut result = Vec::new();
                 match msg{
                     ForthVal::List(vec) => {
                         for v in vec{
-                            if v.to_int().unwrap() < 127{
-                                let c: char = (v.to_int().unwrap() as u8) as char;
+                            let v = v.to_int()?;
+                            if (0..127).contains(&v){
+                                let c: char = (v as u8) as char;
                                 result.push(ForthVal::Str(format!("{}", c)));
                             }
                         }
-                        return ForthVal::List(result);
+                        return Ok(ForthVal::List(result));
                     },
-                    _ => ForthVal::Err(format!("Can't convert to char {:?}", msg))
+                    _ => Err(ForthErr::mismatch("list", msg))
                 }
             });
         
         dict.insert("to_int",
             |ws|{
-                // TODO define unary math more generally
-                to_int(&ws.pop().unwrap()).unwrap()
+                to_int(&ws.pop()?)
             }
         );
         
         dict.insert("write_bin",
             |ws|{
-                if let ForthVal::Str(filename) = ws.pop().unwrap(){
-                    let _result = ws.mem.to_bin(&filename);
-                    return ForthVal::Null;
+                let v = ws.pop()?;
+                if let ForthVal::Str(filename) = v{
+                    match ws.mem.to_bin(&filename){
+                        Ok(_) => Ok(ForthVal::Null),
+                        Err(e) => Err(ForthErr::new(ErrKind::File, e.to_string()))
+                    }
                 }
                 else{
-                    return ForthVal::Err(format!("Invalid data type"));
+                    return Err(ForthErr::mismatch("string", &v));
                 }
-            }  
+            }
         );
         
-        dict.insert("collect",
-            |ws|{
-               let gen = ws.pop().unwrap();
-               let len = ws.pop().unwrap().to_int().unwrap() as usize;
-               match gen{
-                   ForthVal::Generator(mut gen) => {
-                       let mut result = Vec::new();
-                       for _i in 0..len{
-                           result.push(gen.next())
-                       }
-                       result.reverse();
-                       ForthVal::List(result)
-                   },
-                    _ => ForthVal::Err(format!("Can only collect generator, got {:?}", gen))
-               }
-            });
-            
         dict.insert("len",
             |ws|{
-                let value = ws.pop().unwrap();
+                let value = ws.pop()?;
                 match value{
-                    ForthVal::List(v) => ForthVal::Int(v.len() as i64),
-                    _ => ForthVal::Err(format!("Can only get len of list, got {:?}", value))
+                    ForthVal::List(v) => Ok(ForthVal::Int(v.len() as i64)),
+                    // Samples left in a finite generator
+                    ForthVal::Generator(ref gen) => match gen.remaining(){
+                        Some(n) => Ok(ForthVal::Int(n as i64)),
+                        None => Err(ForthErr::new(ErrKind::Invalid, "endless generator has no length"))
+                    },
+                    _ => Err(ForthErr::mismatch("list", &value))
                 }
-            }    
+            }
         );
-            
+        
         dict.insert("repeat",
             |ws|{
-                let value = ws.pop().unwrap();
-                let len = ws.pop().unwrap().to_int().unwrap() as usize;
+                let value = ws.pop()?;
+                let len = pop_index(ws)?;
                 let mut result = Vec::new();
                 for _i in 0..len{
                     result.push(value.clone());
                 }
-                ForthVal::List(result)
-            }  
+                Ok(ForthVal::List(result))
+            }
         );
         
         dict.insert("stack_to_list",
             |ws|{
-                let mut result = Vec::new();
                 if ws.len() == 0{
-                    return ForthVal::Err("Stack empty".to_string());
+                    return Err(ForthErr::underflow());
                 }
-                for _i in 0..ws.len(){
-                    result.push(ws.pop().unwrap());
+                let n = ws.len();
+                Ok(ForthVal::List(pop_n(ws, n)?))
+            }
+        );
+        
+        // ~[ ... ] collects the values left by the words inside
+        dict.insert("~[",
+            |ws|{
+                ws.marks.push(ws.len());
+                Ok(ForthVal::Null)
+            }
+        );
+        
+        dict.insert("]",
+            |ws|{
+                let mark = ws.marks.pop()
+                    .ok_or_else(|| ForthErr::new(ErrKind::Parse, "Got end of list before start of list"))?;
+                if ws.len() < mark{
+                    return Err(ForthErr::new(ErrKind::Underflow, "evaluated list took values from below its start"));
                 }
-                result.reverse();
-                ForthVal::List(result)
+                let n = ws.len() - mark;
+                Ok(ForthVal::List(pop_n(ws, n)?))
             }
         );
         
         dict.insert("remove_from_list",
             |ws|{
-                match ws.pop().unwrap(){
-                    ForthVal::List(mlist) => {
-                        ForthVal::List(mlist[1..mlist.len()].to_vec())
+                match ws.pop()?{
+                    ForthVal::List(mlist) if mlist.len() > 0 => {
+                        Ok(ForthVal::List(mlist[1..mlist.len()].to_vec()))
                     },
-                    _ => ForthVal::Err(format!("Invalid type"))
+                    v => Err(ForthErr::mismatch("non empty list", &v))
                 }
-            }  
+            }
         );
         
         dict.insert("list_group",
             |ws|{
                 let mut result = Vec::new();
-                if let ForthVal::List(a) = ws.pop().unwrap(){
-                    if let ForthVal::List(b) = ws.pop().unwrap(){
-                        for i in 0..a.len(){
+                let a = ws.pop()?;
+                let b = ws.pop()?;
+                if let ForthVal::List(a) = &a{
+                    if let ForthVal::List(b) = &b{
+                        for i in 0..std::cmp::min(a.len(), b.len()){
                             result.push(a[i].append(b[i].clone()));
                         }
-                        return ForthVal::List(result);
+                        return Ok(ForthVal::List(result));
                     }
                 }
-                ForthVal::Err(format!("Unsupported types to group"))
+                Err(ForthErr::new(ErrKind::TypeMismatch,
+                    format!("Unsupported types to group {:?} {:?}", a, b)))
             }
         );
         
         dict.insert("unwrap",
             |ws|{
-                let mlist = ws.pop().unwrap();
-                ForthVal::Vector(unwrap(ws, &mlist))
-            }  
+                let mlist = ws.pop()?;
+                Ok(ForthVal::Vector(unwrap(ws, &mlist)))
+            }
         );
         
         dict.insert("read",
             |ws|{
-                if let Some(addr) = ws.pop(){
-                    let addr = addr.to_int().unwrap();
-                    let result = ws.device.borrow_mut().read(addr as u32);
-                    if let Ok(data) = result{
-                        ForthVal::Int(data as i64)
-                    }
-                    else{
-                        ForthVal::Err("Could not read addr".to_string())
-                    }
-                }
-                else{
-                    ForthVal::Err("Stack empty".to_string())
+                let addr = ws.pop_int()?;
+                let result = ws.device.borrow_mut().read(addr as u32);
+                match result{
+                    Ok(data) => Ok(ForthVal::Int(data as i64)),
+                    Err(e) => Err(ForthErr::new(ErrKind::Device,
+                        format!("Could not read addr {}: {}", addr, e)))
                 }
             }
         );
         
         dict.insert("write",
             |ws|{
-                let mut addr = ws.pop().unwrap().to_int().unwrap() as u32;
-                let data = ws.pop().unwrap();
+                let mut addr = ws.pop_int()? as u32;
+                let data = ws.pop()?;
                 
-                match data{
-                    ForthVal::Int(v) => {
-                        let _result = ws.device.borrow_mut()
-                        .write(addr as u32, v as u32);
+                let result = match data{
+                    ForthVal::Int(_) => {
+                        let v = data.to_u32()?;
+                        ws.device.borrow_mut()
+                        .write(addr as u32, v)
                     },
                     ForthVal::List(vals) => {
-                        for i in 0..vals.len(){
-                            let v = vals[i].to_int().unwrap();
-                            let _result = ws.device.borrow_mut().write(addr, v as u32);
+                        let mut result = Ok(0);
+                        for v in vals{
+                            let v = v.to_u32()?;
+                            result = ws.device.borrow_mut().write(addr, v);
+                            if result.is_err(){
+                                break;
+                            }
                             addr += 4;
                         }
+                        result
                     },
                     _ => {
-                        return ForthVal::Err(format!("Unsupported data for write {:?}", data));
+                        return Err(ForthErr::mismatch("int or list", &data));
                     }
                 };
-                ForthVal::Null
+                match result{
+                    Ok(_) => Ok(ForthVal::Null),
+                    Err(e) => Err(ForthErr::new(ErrKind::Device, e))
+                }
             }
         );
         
         dict.insert("@", |ws|{
-            let addr = match ws.pop(){
-                Some(ForthVal::Var(loc)) => {
+            let addr = match ws.pop()?{
+                ForthVal::Var(loc) => {
                     match loc{
                         Location::Local(a) => a,
                         Location::Client(addr, _) => {
-                            return match ws.mem.access_client(addr as usize){
-                                Ok(v) => v,
-                                Err(v) => v
-                            };
+                            return ws.mem.access_client(addr as usize);
                         }
                     }
                 },
-                Some(ForthVal::Int(a)) => a as usize,
-                _ => {
-                    return ForthVal::Err("Can't use as address".to_string())
+                ForthVal::Int(a) if a >= 0 => a as usize,
+                v => {
+                    return Err(ForthErr::mismatch("address", &v));
                 }
             };
             if let Some(v) = ws.mem.access_local(addr){
-                v.clone()
+                Ok(v.clone())
             }
             else{
-                ForthVal::Err(format!("No variable at location {}", addr))
+                Err(ForthErr::new(ErrKind::Index, format!("No variable at location {}", addr)))
             }
         });
         
         dict.insert(
             "!",
             |ws|{
-                let addr = match ws.pop(){
-                Some(ForthVal::Var(loc)) => {
+                let addr = match ws.pop()?{
+                ForthVal::Var(loc) => {
                     match loc{
                         Location::Local(a) => a,
                         Location::Client(addr, _) => {
@@ -551,53 +1466,62 @@ impl Workspace{
                         }
                     }
                 },
-                Some(ForthVal::Int(a)) => a as usize,
-                _ => {
-                    return ForthVal::Err("Can't use as address".to_string())
+                ForthVal::Int(a) if a >= 0 => a as usize,
+                v => {
+                    return Err(ForthErr::mismatch("address", &v));
                 }
             };
-            return ForthVal::Int(addr as i64);
+            return Ok(ForthVal::Int(addr as i64));
             }
         );
         
         dict.insert(
             "jal%",
             |ws|{
-                if let Some(ForthVal::Int(rd)) = ws.pop(){
-                    if let Some(ForthVal::Meta(name)) = ws.pop(){
-                        return ForthVal::Promise((name, AsmPromise::JAL(rd as u32)));
-                    }
+                let rd = ws.pop_int()?;
+                match ws.pop()?{
+                    ForthVal::Meta(name) => Ok(ForthVal::Promise((name, AsmPromise::JAL(rd as u32)))),
+                    v => Err(ForthErr::mismatch("label", &v))
                 }
-                return ForthVal::Err(format!("Invalid arguments"));
             }
         );
         
         dict.insert(
             "decode",
             |ws|{
-                if let Some(v) = ws.pop(){
-                    match v{
-                        ForthVal::Int(v) => {
-                            if let Some(d) = decode(v as u32){
-                                return ForthVal::Str(d);
-                            }
-                            else{
-                                return ForthVal::Err(format!("Not valid riscv instruction"))
-                            }
-                        },
-                        _ => {
-                            return ForthVal::Err(format!("Not valid type for decode {:?}", v))
-                        }
-                    }
+                let v = ws.pop_int()?;
+                if let Some(d) = decode(v as u32){
+                    return Ok(ForthVal::Str(d));
                 }
                 else{
-                    return ForthVal::Err(format!("Stack empty"))
+                    return Err(ForthErr::new(ErrKind::Invalid, format!("Not valid riscv instruction {:#x}", v)))
                 }
             }
         );
         
         // Basic generators
         dict.insert_generator::<Natural>("natural");
+        dict.insert_generator::<Ramp>("ramp");
+        dict.insert_generator::<Oscillator<Sine>>("sine");
+        dict.insert_generator::<Oscillator<Saw>>("saw");
+        dict.insert_generator::<Oscillator<Triangle>>("triangle");
+        dict.insert_generator::<Oscillator<Square>>("square");
+        dict.insert_generator::<Oscillator<Pulse>>("pulse");
+        dict.insert_generator::<WhiteNoise>("white_noise");
+        dict.insert_generator::<PinkNoise>("pink_noise");
         
+        // Used by oscillators made after it is set
+        dict.insert("sample_rate", |ws|{
+            Ok(ForthVal::Float(ws.sample_rate))
+        });
+        dict.insert("set_sample_rate", |ws|{
+            let rate = ws.pop()?.to_float()?;
+            if rate.is_nan() || rate <= 0.0{
+                return Err(ForthErr::new(ErrKind::Invalid, format!("bad sample rate {}", rate)));
+            }
+            ws.sample_rate = rate;
+            Ok(ForthVal::Null)
+        });
+    
     }
-}
\ No newline at end of file
+}
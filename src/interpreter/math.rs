@@ -1,12 +1,73 @@
-use crate::types::{FloatOp, ForthVal, IntOp};
+use crate::types::{BigOp, ErrKind, FloatOp, ForthErr, ForthRet, ForthVal, IntOp, UnaryFloatOp, UnaryIntOp};
 use super::WorkspaceContext;
 
 use std::rc::Rc;
 
-pub fn binary_op(fi: IntOp, ff: FloatOp) -> Rc<dyn Fn(&mut WorkspaceContext) -> ForthVal>{
+/// Sign extend the low 32 bits, as RV32 registers hold them
+pub fn wrap32(v: i64) -> i64{
+    v as i32 as i64
+}
+
+/// Operation on the top two values
+/// In 32 bit mode ints are wrapped before and after
+pub fn binary_op(fi: IntOp, ff: FloatOp) -> Rc<dyn Fn(&mut WorkspaceContext) -> ForthRet>{
+    big_op(fi, ff, None)
+}
+
+/// Operation on the top two values which also takes big ints
+pub fn big_op(fi: IntOp, ff: FloatOp, fb: Option<BigOp>) -> Rc<dyn Fn(&mut WorkspaceContext) -> ForthRet>{
     Rc::new(move |ws|{
-       let a = ws.pop().unwrap();
-       let b = ws.pop().unwrap();
-       a.operate(&b, fi, ff).unwrap()
+       if ws.len() < 2{
+           return Err(ForthErr::underflow());
+       }
+       let a = ws.pop()?;
+       let b = ws.pop()?;
+       if ws.wrap32{
+           let result = ws.wrap(a).operate(&ws.wrap(b), fi, ff, fb)?;
+           return Ok(ws.wrap(result));
+       }
+       a.operate(&b, fi, ff, fb)
    })
+}
+
+/// Operation on the top value
+pub fn unary_op(fi: Option<UnaryIntOp>, ff: UnaryFloatOp) -> Rc<dyn Fn(&mut WorkspaceContext) -> ForthRet>{
+    Rc::new(move |ws|{
+        let a = ws.pop()?;
+        if ws.wrap32{
+            let result = ws.wrap(a).operate_unary(fi, ff)?;
+            return Ok(ws.wrap(result));
+        }
+        a.operate_unary(fi, ff)
+    })
+}
+
+/// Check a shift count is within the integer width
+pub fn shift_count(n: i64, width: u32) -> Result<u32, ForthErr>{
+    if n < 0 || n >= width as i64{
+        return Err(ForthErr::new(ErrKind::Range, format!("can't shift by {} in {} bits", n, width)));
+    }
+    Ok(n as u32)
+}
+
+/// Shift with the operation for the current integer width
+/// Counts outside the width are errors, except on big ints
+pub fn shift_op(fi32: IntOp, fi64: IntOp, ff: FloatOp, fb: Option<BigOp>) -> Rc<dyn Fn(&mut WorkspaceContext) -> ForthRet>{
+    let op32 = big_op(fi32, ff, fb);
+    let op64 = big_op(fi64, ff, fb);
+    Rc::new(move |ws|{
+        let width = if ws.wrap32 {32} else {64};
+        // Float values are shifted as ints
+        if let (Some(ForthVal::Float(_)), Some(count)) = (ws.len().checked_sub(2).and_then(|i| ws.peek(i)), ws.last()){
+            if let ForthVal::Int(_) | ForthVal::Float(_) = count{
+                shift_count(count.to_int()?, width)?;
+            }
+        }
+        if ws.wrap32{
+            op32(ws)
+        }
+        else{
+            op64(ws)
+        }
+    })
 }
\ No newline at end of file
@@ -1,7 +1,7 @@
 /* Virtual memory with arguments names */
 // TODO have this map to both local and client memory correctly
 use std::collections::HashMap;
-use crate::types::{ForthVal, AsmPromise};
+use crate::types::{AsmPromise, ErrKind, ForthErr, ForthRet, ForthVal};
 use crate::drivers::DeviceInterface;
 use crate::asm;
 
@@ -42,8 +42,8 @@ impl VariableMemory{
     fn write_to_mem(&mut self, addr: u32, offset: u32, value: &ForthVal) -> Result<u32, Box<dyn Error>>{
         let hp = addr+offset;
         match value{
-            ForthVal::Int(v) => {
-                self.driver.borrow_mut().write(hp, *v as u32)?;
+            ForthVal::Int(_) => {
+                self.driver.borrow_mut().write(hp, value.to_u32()?)?;
                 Ok(1)
             },
             ForthVal::List(vals) => {
@@ -71,34 +71,46 @@ impl VariableMemory{
         self.local.get(v)
     }
     
-    pub fn access_client(&self, addr: usize) -> Result<ForthVal, ForthVal>{
+    pub fn access_client(&self, addr: usize) -> ForthRet{
         let mut d = self.driver.borrow_mut();
         if let Ok(_) = d.lock(){
             let result = d.read((addr+reg::OFFSET) as u32);
             let _result = d.unlock();
             match result{
                 Ok(v) => Ok(ForthVal::Int(v as i64)),
-                Err(s) => Err(ForthVal::Err(s))
+                Err(s) => Err(ForthErr::new(ErrKind::Device, s))
             }
         }
         else{
-            Err(ForthVal::Err("Couldn't get lock".to_string()))
+            Err(ForthErr::new(ErrKind::Device, "Couldn't get lock"))
         }
     }
     
-    pub fn get(&mut self, s: &String) -> Option<ForthVal>{
+    pub fn get(&self, s: &String) -> Option<ForthVal>{
         self.names.get(s).map(|loc|{ForthVal::Var(loc.clone())})
     }
     
     pub fn assign_local(&mut self, name: &String, value: &ForthVal){
         // TODO check for already named value
-        let loc = match self.names.get(name){
-            Some(Location::Local(v)) => *v,
+        match self.names.get(name){
+            Some(Location::Local(v)) => {
+                // Reassigning keeps the same location
+                self.local[*v] = value.clone();
+            },
             Some(Location::Client(_, _)) => panic!("Client var already exists with that name"),
-            None => self.local.len() 
-        };
-        self.local.push(value.clone());
-        self.names.insert(name.clone(), Location::Local(loc));
+            None => {
+                self.names.insert(name.clone(), Location::Local(self.local.len()));
+                self.local.push(value.clone());
+            }
+        }
+    }
+    
+    /// Get a local variable by name
+    pub fn local_mut(&mut self, name: &String) -> Option<&mut ForthVal>{
+        match self.names.get(name){
+            Some(Location::Local(v)) => self.local.get_mut(*v),
+            _ => None
+        }
     }
     
     pub fn assign_client(&mut self, name: &String, value: &ForthVal) -> Result<(), String>{
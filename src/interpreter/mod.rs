@@ -3,7 +3,7 @@ use std::cell::RefCell;
 
 use crate::generator::*;
 use crate::reader::{self, read_lines};
-use crate::types::{ForthErr, ForthVal};
+use crate::types::{ErrKind, ForthErr, ForthRet, ForthVal};
 
 use crate::drivers::{Serial, RiscMock, DeviceInterface};
 
@@ -14,27 +14,41 @@ mod dictionary;
 pub mod math;
 mod stack;
 mod alt;
+mod control;
 pub mod mem;
+pub mod vm;
 
 use stack::Stack;
 use dictionary::*;
 use functions::*;
 
 use alt::{AltCollect, AltMode};
-use mem::VariableMemory;
+use control::LoopFrame;
+use mem::{Location, VariableMemory};
+use vm::{Frame, Op};
 
 use crate::reg;
 
 // function call
-pub type ForthFn = fn(&mut WorkspaceContext) -> ForthVal;
-pub type ForthFnGen = Rc<dyn Fn(&mut WorkspaceContext) -> ForthVal>;
+pub type ForthFn = fn(&mut WorkspaceContext) -> ForthRet;
+pub type ForthFnGen = Rc<dyn Fn(&mut WorkspaceContext) -> ForthRet>;
+/// Builtin which needs the interpreter, such as words running other words
+pub type ForthExec = fn(&mut Workspace) -> Result<(), ForthErr>;
 
 
 
+/// Default limit on nested compiled words
+const DEFAULT_DEPTH: usize = 4096;
+/// Limit on programs run from inside builtins
+const MAX_NESTING: usize = 128;
+/// Samples per second for generators, as the mixer uses
+const DEFAULT_SAMPLE_RATE: f64 = 48000.0;
+
 #[derive(Clone)]
 pub enum ForthRoutine{
     Prim(ForthFnGen),
-    Compiled(Vec<ForthVal>)
+    Compiled(Rc<[Op]>),
+    Exec(ForthExec)
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -42,7 +56,6 @@ enum Mode{
     NORMAL,
     ALT,
     NEEDS,
-    CONDITION,
 }
 
 enum Namespace{
@@ -54,10 +67,29 @@ pub struct WorkspaceContext{
     pub stack: Stack,
     pub reply: Stack,
     
+    // Counted loop indices
+    pub loops: Vec<LoopFrame>,
+    // Return stack, separate from replies
+    pub rstack: Stack,
+    // Stack depths where evaluated lists start
+    pub marks: Vec<usize>,
+    // Most compiled words which can be nested
+    pub max_depth: usize,
+    // Base for reading and printing numbers
+    pub base: u32,
+    // Floats closer than this are equal
+    pub tolerance: f64,
+    // Wrap integers to 32 bits like RV32
+    pub wrap32: bool,
+    // Samples per second for oscillators
+    pub sample_rate: f64,
+    
     pub alt: Rc<RefCell<Option<AltCollect>>>,
     
     // For declaring new words
     mode: Mode,
+    // Inside a ( comment which continues on the next line
+    comment: bool,
     pub mem: VariableMemory, // TODO make this more better :)
     pub dictionary: Dictionary,
     
@@ -74,7 +106,16 @@ impl WorkspaceContext{
         Self{
             stack: Stack::new(s.clone()),
             reply: Stack::new(s.clone()),
+            loops: Vec::new(),
+            rstack: Stack::new(s.clone()),
+            marks: Vec::new(),
+            max_depth: DEFAULT_DEPTH,
+            base: 10,
+            tolerance: 0.0,
+            wrap32: false,
+            sample_rate: DEFAULT_SAMPLE_RATE,
             mode: Mode::NORMAL,
+            comment: false,
             
             mem: VariableMemory::new(device.clone(), reg::HEAP as u32),
             alt: Rc::new(RefCell::new(None)),
@@ -95,25 +136,75 @@ impl WorkspaceContext{
     }
     
     /// pop value from stack
-    pub fn pop(&mut self) -> Option<ForthVal>{
-        self.stack.pop()
+    pub fn pop(&mut self) -> ForthRet{
+        match self.stack.pop(){
+            Some(v) => Ok(v),
+            None => Err(ForthErr::underflow())
+        }
     }
 
+    /// Wrap integers to the integer width
+    pub fn wrap(&self, v: ForthVal) -> ForthVal{
+        if !self.wrap32{
+            return v;
+        }
+        match v{
+            ForthVal::Int(n) => ForthVal::Int(math::wrap32(n)),
+            ForthVal::List(values) => ForthVal::List(values.into_iter().map(|v| self.wrap(v)).collect()),
+            _ => v
+        }
+    }
+    
+    /// form.field reads a field of a form variable and form.field! stores to it
+    fn property(&mut self, name: &String, path: &str) -> Result<(), ForthErr>{
+        let form = match self.mem.local_mut(name){
+            Some(ForthVal::Form(form)) => form,
+            Some(v) => {return Err(ForthErr::mismatch("form", v));},
+            None => {return Err(ForthErr::new(ErrKind::UnknownWord, name.clone()));}
+        };
+        match path.strip_suffix('!'){
+            Some(path) => {
+                let v = self.stack.pop().ok_or_else(ForthErr::underflow)?;
+                form.set_path(path, v)
+            },
+            None => {
+                let v = form.get_path(path)?.clone();
+                self.push(v);
+                Ok(())
+            }
+        }
+    }
+    
+    /// pop integer from stack
+    pub fn pop_int(&mut self) -> Result<i64, ForthErr>{
+        self.pop()?.to_int()
+    }
+    
     /// read top of stack
     fn last(&self) -> Option<&ForthVal>{
         self.stack.last()
     }
     
     /// read n of stack
-    fn peek(&self, index: usize) -> Option<&ForthVal>{
+    pub(crate) fn peek(&self, index: usize) -> Option<&ForthVal>{
         self.stack.get(index)
     }
     
     /// length of stack
-    fn len(&self) -> usize{
+    pub fn len(&self) -> usize{
         self.stack.len()
     }
     
+    /// Write text to the reply, joining it to text already there
+    pub fn write_text(&mut self, s: &str) -> Result<(), ForthErr>{
+        if let Some(ForthVal::Text(text)) = self.reply.last_mut()?{
+            text.push_str(s);
+            return Ok(());
+        }
+        self.reply.push(ForthVal::Text(s.to_string()));
+        Ok(())
+    }
+    
     fn set_alt(&mut self, alt: AltCollect){
         //*std::cell::RefCell::<_>::borrow_mut(&self.alt) = Some(alt);
         *self.alt.borrow_mut() = Some(alt);
@@ -121,10 +212,33 @@ impl WorkspaceContext{
     }
 }
 
+/// Print reply to the terminal
+/// Values are separated by spaces, text is written as is
+pub fn print_reply(reply: &[ForthVal]){
+    let mut out = String::new();
+    for r in reply{
+        match r{
+            ForthVal::Text(s) => out.push_str(s),
+            _ => {
+                out.push_str(&r.to_string());
+                out.push(' ');
+            }
+        }
+    }
+    if !out.ends_with('\n'){
+        out.push('\n');
+    }
+    print!("{}", out);
+}
+
 /// Forth workspace context
 pub struct Workspace{
     pub ctx: WorkspaceContext,
-    alt: Rc<RefCell<Option<AltCollect>>>
+    alt: Rc<RefCell<Option<AltCollect>>>,
+    // Compiled words being run
+    frames: Vec<Frame>,
+    // Programs started by builtins such as catch, these use the Rust stack
+    nesting: usize
 }
 
 impl Workspace{
@@ -133,7 +247,9 @@ impl Workspace{
         let alt = ctx.alt.clone();
         Self{
             ctx: ctx,
-            alt: alt
+            alt: alt,
+            frames: Vec::new(),
+            nesting: 0
         }
     }
     
@@ -147,7 +263,7 @@ impl Workspace{
     pub fn prompt(&self) -> &str{
         match self.ctx.mode{
             Mode::NEEDS => "needs>",
-            Mode::CONDITION => "?>",
+            _ if self.ctx.comment => "(>",
             _ => {
                 if self.ctx.dictionary.is_local(){
                     "riscv>"
@@ -163,16 +279,14 @@ impl Workspace{
         match self.ctx.mode{
             Mode::NORMAL => self.run(v),
             Mode::ALT => {
+                let mut finished = None;
                 if let Some(alt) = self.alt.borrow_mut().as_mut(){
                     let result = alt.next(&mut self.ctx, v);
                     match result{
                         Ok(AltMode::DONE) => {
                             // Finish definition
-                            match alt.finish(&mut self.ctx){
-                                Err(e) => {return Err(e);},
-                                _ => ()
-                            };
                             self.ctx.mode = Mode::NORMAL;
+                            finished = alt.finish(&mut self.ctx)?;
                         },
                         Err(e) => {
                             self.ctx.mode = Mode::NORMAL;
@@ -185,28 +299,11 @@ impl Workspace{
                     };
                 }
                 //self.alt.as_mut().unwrap().next(&self.ctx, v);
-                Ok(())
-            },
-            Mode::CONDITION => {
-                match v{
-                    ForthVal::Sym(s) =>{
-                        if s == "then"{
-                            self.ctx.mode = Mode::NORMAL;
-                        }
-                        Ok(())
-                    },
-                    ForthVal::Func(id) =>{
-                      let ending_id = self.ctx.dictionary.then_id();
-                      if *id == ending_id{
-                          self.ctx.mode = Mode::NORMAL;
-                      }
-                      Ok(())
-                    },
-                    _ => {
-                        // Ignore
-                        Ok(())
-                    }
-                }  
+                match finished{
+                    // Immediate blocks are run as soon as they are closed
+                    Some(v) => self.run(&v),
+                    None => Ok(())
+                }
             },
             Mode::NEEDS => {
                 println!("Loading file {}", v.to_string());
@@ -218,7 +315,7 @@ impl Workspace{
                     ForthVal::Str(_s) => {
                         self.read_file(format!("{}", v.to_string()).as_str());
                     },
-                    _ => {return Err(ForthErr::ErrString(format!("Invalid file {:?}", v)));}
+                    _ => {return Err(ForthErr::mismatch("file name", v));}
                 }
                 Ok(())
             }
@@ -227,16 +324,22 @@ impl Workspace{
     
     /// Read line from interpreter
     pub fn read(&mut self, s: &str) -> Result<Vec<ForthVal>, ForthErr>{
-        let mut reader = reader::ForthReader::from_line(s);
+        let mut reader = reader::ForthReader::from_line(s, self.ctx.comment);
+        self.ctx.comment = reader.in_comment();
         self.ctx.reply.clear();
         while !reader.is_done(){
-            let token = reader.next();
+            let token = reader.next(self.ctx.base);
             match token{
                 Ok(v) => {
-                    self.interpret_token(&v)?;
+                    if let Err(err) = self.interpret_token(&v){
+                        // Unwind any loops and return values left by the error
+                        self.ctx.loops.clear();
+                        self.ctx.rstack.clear();
+                        self.ctx.marks.clear();
+                        return Err(err);
+                    }
                 },
                 Err(err) => {
-                    println!("Error {:#?}", err);
                     return Err(err)
                 }
             }
@@ -244,33 +347,6 @@ impl Workspace{
         Ok(self.ctx.reply.get_local().clone())
     }
     
-    fn run_routine(&mut self, routine: &ForthRoutine) -> Result<(), ForthErr>{
-        match routine{
-            ForthRoutine::Prim(f) => {
-                // Primitive words can be called directly
-                let result = f.clone()(&mut self.ctx);
-                match result{
-                    ForthVal::Null => (),
-                    ForthVal::Vector(values) =>{
-                      for v in values{
-                          self.ctx.push(v);
-                      }  
-                    },
-                    ForthVal::Err(s) => {
-                        return Err(ForthErr::ErrString(s.clone()))
-                    }
-                    _ => self.ctx.push(result)
-                };
-            },
-            ForthRoutine::Compiled(program) => {
-                for p in program.clone(){
-                    self.interpret_token(&p)?;
-                }
-            }
-        };
-        Ok(())
-    }
-    
     /// Read things from a forth line
     pub fn run(&mut self, val: &ForthVal) -> Result<(), ForthErr>{
         // TODO make this reply more detailed
@@ -280,27 +356,38 @@ impl Workspace{
                 // General symbol type
                 // This is normally a  word
                 if let Some(var) = self.ctx.mem.get(s){
-                    self.ctx.push(var);
+                    // Local variables give their value, client ones their address
+                    match var{
+                        ForthVal::Var(Location::Local(a)) => {
+                            let v = self.ctx.mem.access_local(a).cloned().unwrap_or(ForthVal::Null);
+                            self.ctx.push(v);
+                        },
+                        _ => self.ctx.push(var)
+                    }
                     return Ok(())
                 }
-                else if let Some(routine) = self.ctx.dictionary.get_fn(s){
+                else if let Some(id) = self.ctx.dictionary.get_id(s){
                     // run function
-                    return self.run_routine(&routine.clone());
+                    return self.call(*id);
+                }
+                else if let Some(v) = reader::parse_number(s, self.ctx.base)?{
+                    // Number in a base with letters as digits
+                    self.ctx.push(v);
+                    return Ok(())
                 }
                 else{
-                    return Err(ForthErr::ErrString(format!("Unknown word {}", s)));
+                    return Err(ForthErr::new(ErrKind::UnknownWord, s.clone()));
                 }
             },
             ForthVal::Func(f) => {
                 // This is for compiled functions
-                let routine = self.ctx.dictionary.get_fn_from_id(f).unwrap();
-                return self.run_routine(&routine.clone());
+                return self.call(*f);
             },
             ForthVal::Meta(m) =>{
                 // Use backtick character to get information about functions
                 if let Some(id) = self.ctx.dictionary.get_id(m){
                     match self.ctx.dictionary.get_fn_from_id(id).unwrap(){
-                        ForthRoutine::Prim(_f) => {
+                        ForthRoutine::Prim(_) | ForthRoutine::Exec(_) => {
                             self.ctx.reply.push(ForthVal::Str(format!("Builtin function {}", m)));
                             self.ctx.reply.push(ForthVal::Str(format!("Id: {}", id)));
                         },
@@ -312,8 +399,8 @@ impl Workspace{
                 }
                 self.ctx.push(val.clone());
             },
-            ForthVal::Property((form, field)) => {
-              todo!("Property is not implemented");
+            ForthVal::Property((name, path)) => {
+                return self.ctx.property(name, path);
             },
             ForthVal::Callable(m) => {
                 // Function pointer
@@ -325,6 +412,15 @@ impl Workspace{
         Ok(())
     }
     
+    /// Run an execution token
+    pub fn execute(&mut self, xt: &ForthVal) -> Result<(), ForthErr>{
+        match xt{
+            ForthVal::Func(_) | ForthVal::Callable(_) => self.run(xt),
+            ForthVal::Meta(name) => self.run(&ForthVal::Sym(name.clone())),
+            _ => Err(ForthErr::mismatch("execution token", xt))
+        }
+    }
+    
     /// Read forth program from file
     pub fn read_file(&mut self, filename: &str){
         if let Ok(lines) = read_lines(filename){
@@ -333,31 +429,25 @@ impl Workspace{
                 match self.read(line.as_str()){
                     Ok(reply) => {
                         if reply.len() > 0{
-                            for r in reply{
-                                print!("{} ", r.to_string());
-                            }
-                            print!("\n");
+                            print_reply(&reply);
                         }
                     },
                     Err(err) => {
-                        match err{
-                            ForthErr::ErrString(s) => {
-                                println!("Error: {:?}", s);
-                            },
-                            ForthErr::ErrForthVal(v) => {
-                                println!("Error on value: {:?}", v);
-                            }
-                        }
+                        println!("Error: {}", err);
                     }
                 }
             }
+            if self.ctx.comment{
+                println!("Error: {}", ForthErr::new(ErrKind::Parse, format!("Unclosed ( comment in {}", filename)));
+                self.ctx.comment = false;
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod tests{
-    use crate::types::ForthVal;
+    use crate::types::{ErrKind, ForthVal};
 
     use super::Workspace;
     
@@ -418,4 +508,844 @@ mod tests{
             _ => panic!("Unexpected return {}", result[0].to_string())
         }
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn if_else(){
+        let mut ws = Workspace::standard();
+        let _ = ws.read(": sign 0 < if -1 else 1 then ;").expect("Response");
+        let result = ws.read("-5 sign . 5 sign .").expect("Response");
+        assert_eq!(result[0].to_int().unwrap(), -1);
+        assert_eq!(result[1].to_int().unwrap(), 1);
+    }
+    
+    #[test]
+    fn nested_if(){
+        let mut ws = Workspace::standard();
+        let _ = ws.read(": classify dup 0 < if 0 * else 10 > if 2 else 1 then then ;").expect("Response");
+        let result = ws.read("-3 classify . 5 classify . 50 classify .").expect("Response");
+        assert_eq!(result[0].to_int().unwrap(), 0);
+        assert_eq!(result[1].to_int().unwrap(), 1);
+        assert_eq!(result[2].to_int().unwrap(), 2);
+    }
+    
+    #[test]
+    fn top_level_if(){
+        let mut ws = Workspace::standard();
+        let result = ws.read("1 if 2 if 3 . then else 4 . then").expect("Response");
+        assert_eq!(result[0].to_int().unwrap(), 3);
+        // Structures can span lines
+        let _ = ws.read("0 if 5 .").expect("Response");
+        let result = ws.read("else 6 . then").expect("Response");
+        assert_eq!(result[0].to_int().unwrap(), 6);
+    }
+    
+    #[test]
+    fn unmatched_then(){
+        let mut ws = Workspace::standard();
+        assert!(ws.read("then").is_err());
+        assert!(ws.read(": bad 1 then ;").is_err());
+        assert!(ws.read(": bad 1 if ;").is_err());
+    }
+    
+    #[test]
+    fn do_loop(){
+        let mut ws = Workspace::standard();
+        let _ = ws.read(": sum 0 swap 0 do i + loop ;").expect("Response");
+        let result = ws.read("5 sum .").expect("Response");
+        assert_eq!(result[0].to_int().unwrap(), 10);
+        // Top level loops
+        let result = ws.read("3 0 do i . loop").expect("Response");
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[2].to_int().unwrap(), 2);
+    }
+    
+    #[test]
+    fn nested_loop(){
+        let mut ws = Workspace::standard();
+        let result = ws.read("2 0 do 3 0 do j 10 * i + . loop loop").expect("Response");
+        let values: Vec<i64> = result.iter().map(|v| v.to_int().unwrap()).collect();
+        assert_eq!(values, vec![0, 1, 2, 10, 11, 12]);
+    }
+    
+    #[test]
+    fn plus_loop(){
+        let mut ws = Workspace::standard();
+        let result = ws.read("10 0 do i . 3 +loop").expect("Response");
+        let values: Vec<i64> = result.iter().map(|v| v.to_int().unwrap()).collect();
+        assert_eq!(values, vec![0, 3, 6, 9]);
+        let result = ws.read("0 4 do i . -2 +loop").expect("Response");
+        let values: Vec<i64> = result.iter().map(|v| v.to_int().unwrap()).collect();
+        assert_eq!(values, vec![4, 2, 0]);
+    }
+    
+    #[test]
+    fn leave_loop(){
+        let mut ws = Workspace::standard();
+        let _ = ws.read(": first_over 100 0 do i i * 20 > if i leave then loop ;").expect("Response");
+        let result = ws.read("first_over .").expect("Response");
+        assert_eq!(result[0].to_int().unwrap(), 5);
+        assert!(ws.ctx.loops.is_empty());
+    }
+    
+    #[test]
+    fn qdo_skips(){
+        let mut ws = Workspace::standard();
+        let result = ws.read("0 0 ?do i . loop 7 .").expect("Response");
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].to_int().unwrap(), 7);
+    }
+    
+    #[test]
+    fn begin_until(){
+        let mut ws = Workspace::standard();
+        let _ = ws.read(": countdown begin dup . 1 - dup 0 < until ;").expect("Response");
+        let result = ws.read("2 countdown").expect("Response");
+        let values: Vec<i64> = result.iter().map(|v| v.to_int().unwrap()).collect();
+        assert_eq!(values, vec![2, 1, 0]);
+    }
+    
+    #[test]
+    fn begin_while_repeat(){
+        let mut ws = Workspace::standard();
+        // Count halvings until zero
+        let _ = ws.read(": halvings 0 swap begin dup 0 > while 2 / swap 1 + swap repeat ;").expect("Response");
+        let result = ws.read("8 halvings . .").expect("Response");
+        assert_eq!(result[0].to_int().unwrap(), 0);
+        assert_eq!(result[1].to_int().unwrap(), 4);
+        // Top level
+        let result = ws.read("1 begin dup 20 < while 3 * repeat .").expect("Response");
+        assert_eq!(result[0].to_int().unwrap(), 27);
+        // Outside of begin ... while, repeat makes a list
+        let _ = ws.read(": r3 3 5 repeat ;").expect("Response");
+        assert_eq!(top_of(&mut ws, "r3"), "[5, 5, 5]");
+        assert_eq!(top_of(&mut ws, ": twos begin dup 0 > while 1 - repeat drop 3 2 repeat ; 2 twos"), "[2, 2, 2]");
+    }
+    
+    #[test]
+    fn begin_again(){
+        let mut ws = Workspace::standard();
+        // Leave the surrounding loop to get out
+        let _ = ws.read(": first_cube 1 1 0 do begin dup dup dup * * 100 > if leave then 1 + again loop ;").expect("Response");
+        let result = ws.read("first_cube .").expect("Response");
+        assert_eq!(result[0].to_int().unwrap(), 5);
+        assert!(ws.read(": bad begin 1 repeat ;").is_err());
+        assert!(ws.read(": bad 1 while ;").is_err());
+    }
+    
+    #[test]
+    fn return_stack(){
+        let mut ws = Workspace::standard();
+        let _ = ws.read(": under+ >r + r> ;").expect("Response");
+        let result = ws.read("1 2 3 under+ . .").expect("Response");
+        assert_eq!(result[0].to_int().unwrap(), 3);
+        assert_eq!(result[1].to_int().unwrap(), 3);
+        let _ = ws.read(": keep r! 1 + r@ rdrop ;").expect("Response");
+        let result = ws.read("5 keep . .").expect("Response");
+        assert_eq!(result[0].to_int().unwrap(), 5);
+        assert_eq!(result[1].to_int().unwrap(), 6);
+    }
+    
+    #[test]
+    fn return_stack_unbalanced(){
+        let mut ws = Workspace::standard();
+        let _ = ws.read(": leaky >r ;").expect("Response");
+        assert!(ws.read("1 leaky").is_err());
+        assert_eq!(ws.ctx.rstack.len(), 0);
+        assert!(ws.read("r>").is_err());
+    }
+    
+    fn stack_of(ws: &mut Workspace, line: &str) -> Vec<i64>{
+        ws.ctx.stack.clear();
+        let _ = ws.read(line).expect("Response");
+        ws.ctx.stack.get_local().iter().map(|v| v.to_int().unwrap()).collect()
+    }
+    
+    /// Top of the stack after reading line, as text
+    fn top_of(ws: &mut Workspace, line: &str) -> String{
+        ws.read(line).expect("Response");
+        ws.ctx.pop().expect("Value").to_string()
+    }
+    
+    #[test]
+    fn stack_words(){
+        let mut ws = Workspace::standard();
+        assert_eq!(stack_of(&mut ws, "1 2 drop"), vec![1]);
+        assert_eq!(stack_of(&mut ws, "1 2 over"), vec![1, 2, 1]);
+        assert_eq!(stack_of(&mut ws, "1 2 3 rot"), vec![2, 3, 1]);
+        assert_eq!(stack_of(&mut ws, "1 2 3 -rot"), vec![3, 1, 2]);
+        assert_eq!(stack_of(&mut ws, "1 2 nip"), vec![2]);
+        assert_eq!(stack_of(&mut ws, "1 2 tuck"), vec![2, 1, 2]);
+        assert_eq!(stack_of(&mut ws, "1 2 3 2 pick"), vec![1, 2, 3, 1]);
+        assert_eq!(stack_of(&mut ws, "1 2 3 2 roll"), vec![2, 3, 1]);
+        assert_eq!(stack_of(&mut ws, "1 2 3 depth"), vec![1, 2, 3, 3]);
+        assert_eq!(stack_of(&mut ws, "0 ?dup 4 ?dup"), vec![0, 4, 4]);
+        assert_eq!(stack_of(&mut ws, "0.0 ?dup depth nip"), vec![1]);
+        assert_eq!(stack_of(&mut ws, "0.5 ?dup depth nip nip"), vec![2]);
+    }
+    
+    #[test]
+    fn double_stack_words(){
+        let mut ws = Workspace::standard();
+        assert_eq!(stack_of(&mut ws, "1 2 2dup"), vec![1, 2, 1, 2]);
+        assert_eq!(stack_of(&mut ws, "1 2 3 2drop"), vec![1]);
+        assert_eq!(stack_of(&mut ws, "1 2 3 4 2swap"), vec![3, 4, 1, 2]);
+        assert_eq!(stack_of(&mut ws, "1 2 3 4 2over"), vec![1, 2, 3, 4, 1, 2]);
+    }
+    
+    #[test]
+    fn stack_underflow(){
+        let mut ws = Workspace::standard();
+        for word in ["dup", "drop", "swap", "over", "rot", "-rot", "nip", "tuck",
+                     "2dup", "2drop", "2swap", "2over", "?dup"]{
+            ws.ctx.stack.clear();
+            assert!(ws.read(word).is_err(), "{} on empty stack", word);
+        }
+        // Values are left alone when there are not enough
+        assert!(ws.read("1 2 3 5 pick").is_err());
+        assert_eq!(ws.ctx.stack.len(), 3);
+        ws.ctx.stack.clear();
+        assert!(ws.read("1 2 rot").is_err());
+        assert_eq!(ws.ctx.stack.len(), 2);
+    }
+    
+    #[test]
+    fn error_kinds(){
+        let mut ws = Workspace::standard();
+        let err = ws.read("+").unwrap_err();
+        assert_eq!(err.kind, ErrKind::Underflow);
+        assert_eq!(err.word, Some("+".to_string()));
+        
+        let err = ws.read("1 \"a\" pick").unwrap_err();
+        assert_eq!(err.kind, ErrKind::TypeMismatch);
+        
+        let err = ws.read("nonsense").unwrap_err();
+        assert_eq!(err.kind, ErrKind::UnknownWord);
+    }
+    
+    #[test]
+    fn error_trace(){
+        let mut ws = Workspace::standard();
+        ws.read(": a + ;").unwrap();
+        ws.read(": b a ;").unwrap();
+        let err = ws.read("1 b").unwrap_err();
+        assert_eq!(err.kind, ErrKind::Underflow);
+        assert_eq!(err.word, Some("+".to_string()));
+        assert_eq!(err.trace, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(err.to_string(), "Stack underflow in + (called from a <- b)");
+    }
+    
+    #[test]
+    fn catch_throw(){
+        let mut ws = Workspace::standard();
+        ws.read(": risky 10 >r 4 5 throw ;").unwrap();
+        ws.read(": safe 7 ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "1 2 `risky catch"), vec![1, 2, 5]);
+        assert_eq!(ws.ctx.rstack.len(), 0);
+        assert_eq!(stack_of(&mut ws, "`safe catch"), vec![7, 0]);
+        ws.read(": zero 0 throw 3 ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "`zero catch"), vec![3, 0]);
+        
+        // Builtin errors are given their throw codes
+        assert_eq!(stack_of(&mut ws, "`+ catch"), vec![-4]);
+        
+        let err = ws.read("3 throw").unwrap_err();
+        assert_eq!(err.kind, ErrKind::Throw(3));
+    }
+    
+    #[test]
+    fn abort_message(){
+        let mut ws = Workspace::standard();
+        ws.read(": check 0 == if abort\"zero given\" then ;").unwrap();
+        let err = ws.read("0 check").unwrap_err();
+        assert_eq!(err.kind, ErrKind::Throw(-2));
+        assert_eq!(err.msg, "zero given");
+        assert_eq!(stack_of(&mut ws, "0 `check catch"), vec![0, -2]);
+        
+        // Space after abort\" is not part of the message
+        let err = ws.read("abort\" spaced\"").unwrap_err();
+        assert_eq!(err.msg, "spaced");
+        assert_eq!(ws.read("abort").unwrap_err().kind, ErrKind::Throw(-1));
+    }
+    
+    #[test]
+    fn recurse_exit(){
+        let mut ws = Workspace::standard();
+        ws.read(": fact dup 1 > if dup 1 - recurse * then ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "5 fact"), vec![120]);
+        
+        ws.read(": clamp dup 10 > if drop 10 exit then 1 + ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "20 clamp 3 clamp"), vec![10, 4]);
+        
+        // Exit from inside a loop drops the loop index
+        ws.read(": first-over 10 0 do i over > if drop i exit then loop drop -1 ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "4 first-over 20 first-over"), vec![5, -1]);
+        assert!(ws.ctx.loops.is_empty());
+        
+        assert_eq!(ws.read("exit").unwrap_err().kind, ErrKind::Compile);
+    }
+    
+    #[test]
+    fn call_depth(){
+        let mut ws = Workspace::standard();
+        ws.read(": count dup 0 > if 1 - recurse then ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "4000 count"), vec![0]);
+        
+        let err = ws.read("5000 count").unwrap_err();
+        assert_eq!(err.kind, ErrKind::CallDepth);
+        assert_eq!(err.word, Some("count".to_string()));
+        assert!(err.trace.is_empty());
+        
+        ws.read("10000 set_max_depth").unwrap();
+        assert_eq!(stack_of(&mut ws, "5000 count"), vec![0]);
+        ws.read("10 set_max_depth").unwrap();
+        assert_eq!(stack_of(&mut ws, "20 `count catch"), vec![20, -5]);
+        
+        // Nesting through catch is limited separately
+        ws.read("4096 set_max_depth").unwrap();
+        ws.read(": deep dup 0 > if 1 - `deep catch drop then ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "50 deep"), vec![0]);
+        // The innermost catch gets the error instead of overflowing
+        assert!(ws.read("1000 deep").is_ok());
+    }
+    
+    #[test]
+    fn string_escapes(){
+        let mut ws = Workspace::standard();
+        ws.read(r#""a\tb\n\"q\" \\ \x41\xc3\xa9""#).unwrap();
+        match ws.ctx.pop(){
+            Ok(ForthVal::Str(s)) => assert_eq!(s, "a\tb\n\"q\" \\ Aé"),
+            _ => panic!("Expected string")
+        }
+        assert_eq!(ws.read(r#""\q""#).unwrap_err().kind, ErrKind::Parse);
+        assert_eq!(ws.read(r#""\x4""#).unwrap_err().kind, ErrKind::Parse);
+        // Strings are text, raw bytes need a byte string
+        assert_eq!(ws.read(r#""\xff""#).unwrap_err().kind, ErrKind::Parse);
+        ws.read(r#"b"W\x01\xffé""#).unwrap();
+        match ws.ctx.pop(){
+            Ok(ForthVal::Bytes(b)) => assert_eq!(b, vec![b'W', 0x01, 0xff, 0xc3, 0xa9]),
+            _ => panic!("Expected bytes")
+        }
+        assert_eq!(top_of(&mut ws, r#"b"a\x00""#), r#"b"a\x00""#);
+        assert_eq!(stack_of(&mut ws, r#"b"ab" b"ab" == b"ab" "ab" =="#), vec![-1, 0]);
+    }
+    
+    #[test]
+    fn text_output(){
+        let mut ws = Workspace::standard();
+        let reply = ws.read(r#"." hello" space 65 emit 66 emit cr "done\x21" type"#).unwrap();
+        assert_eq!(reply.len(), 1);
+        assert_eq!(reply[0].to_string(), "hello AB\ndone!");
+        
+        ws.read(r#": greet ." hi " . ;"#).unwrap();
+        let reply = ws.read("5 greet").unwrap();
+        assert_eq!(reply[0].to_string(), "hi ");
+        assert_eq!(reply[1].to_int().unwrap(), 5);
+        
+        ws.read(r#"s" pushed""#).unwrap();
+        assert_eq!(ws.ctx.pop().unwrap().to_string(), "'pushed'");
+        ws.read("[104 105] type").unwrap();
+        let reply = ws.read(r#"b"ab\xc3\xa9" type"#).unwrap();
+        assert_eq!(reply[0].to_string(), "abé");
+        assert_eq!(ws.read("-1 emit").unwrap_err().kind, ErrKind::Invalid);
+    }
+    
+    #[test]
+    fn number_literals(){
+        let mut ws = Workspace::standard();
+        assert_eq!(stack_of(&mut ws, "0xff x1F -0x10 0b101 b11 0o17 1_000_000"),
+            vec![255, 31, -16, 5, 3, 15, 1000000]);
+        assert_eq!(stack_of(&mut ws, r"'A' '\n' '\x41' ' '"), vec![65, 10, 65, 32]);
+        assert_eq!(stack_of(&mut ws, r"'\xff' 'é'"), vec![255, 233]);
+        assert_eq!(stack_of(&mut ws, "0xffffffffffffffff"), vec![-1]);
+        
+        ws.read("1.5e3 -2.5E-1 1_0.0_5").unwrap();
+        assert_eq!(ws.ctx.pop().unwrap().to_float().unwrap(), 10.05);
+        assert_eq!(ws.ctx.pop().unwrap().to_float().unwrap(), -0.25);
+        assert_eq!(ws.ctx.pop().unwrap().to_float().unwrap(), 1500.0);
+        
+        assert_eq!(ws.read("9223372036854775808").unwrap_err().kind, ErrKind::Parse);
+        assert_eq!(stack_of(&mut ws, "-9223372036854775808"), vec![i64::MIN]);
+        assert_eq!(stack_of(&mut ws, r"'\'' '\\'"), vec![39, 92]);
+        for line in ["'ab'", "'''", "''", "['a' 'bc']"]{
+            assert_eq!(ws.read(line).unwrap_err().kind, ErrKind::Parse, "{}", line);
+        }
+        // Words ending in digits of other bases are not numbers
+        assert_eq!(ws.read("feedbeef").unwrap_err().kind, ErrKind::UnknownWord);
+        assert_eq!(ws.read("1_").unwrap_err().kind, ErrKind::UnknownWord);
+    }
+    
+    #[test]
+    fn number_base(){
+        let mut ws = Workspace::standard();
+        assert_eq!(stack_of(&mut ws, "hex ff 10 -a base decimal"), vec![255, 16, -10, 16]);
+        ws.read("hex [a b] decimal").unwrap();
+        assert_eq!(ws.ctx.pop().unwrap().to_string(), "[10, 11]");
+        assert_eq!(stack_of(&mut ws, "binary 101 0x10 decimal"), vec![5, 16]);
+        // Hex letters are words first
+        ws.read(": add 1 + ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "hex 1 add decimal"), vec![2]);
+        ws.read("hex").unwrap();
+        ws.read(": big ff 1 + ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "decimal big"), vec![256]);
+        
+        let reply = ws.read("[10 11] -16 255 hex . . . decimal 255 .").unwrap();
+        assert_eq!(reply[0].to_string(), "ff -10 [a, b] ");
+        assert_eq!(reply[1].to_int().unwrap(), 255);
+        assert_eq!(stack_of(&mut ws, "36 set_base zz decimal"), vec![1295]);
+        assert_eq!(ws.read("1 set_base").unwrap_err().kind, ErrKind::Invalid);
+    }
+    
+    #[test]
+    fn nested_lists(){
+        let mut ws = Workspace::standard();
+        ws.read(r#"[[1 2] [3 [4]] "a b" x21 name]"#).unwrap();
+        assert_eq!(ws.ctx.pop().unwrap().to_string(), "[[1, 2], [3, [4]], 'a b', 33, name]");
+        assert_eq!(ws.read("[1 2").unwrap_err().kind, ErrKind::Parse);
+        assert_eq!(ws.read("[1 ~[2]]").unwrap_err().kind, ErrKind::Parse);
+    }
+    
+    #[test]
+    fn evaluated_lists(){
+        let mut ws = Workspace::standard();
+        ws.read("256 const MemoryAddress").unwrap();
+        ws.read("5 ~[x21 MemoryAddress 1 2 + [4 5] ~[6 dup]]").unwrap();
+        assert_eq!(ws.ctx.pop().unwrap().to_string(), "[33, 256, 3, [4, 5], [6, 6]]");
+        assert_eq!(ws.ctx.pop().unwrap().to_int().unwrap(), 5);
+        
+        ws.read(": table ~[ 4 0 do i dup * loop ] ;").unwrap();
+        ws.read("table").unwrap();
+        assert_eq!(ws.ctx.pop().unwrap().to_string(), "[0, 1, 4, 9]");
+        
+        assert_eq!(ws.read("]").unwrap_err().kind, ErrKind::Parse);
+        assert_eq!(ws.read("1 ~[ drop ]").unwrap_err().kind, ErrKind::Underflow);
+        assert_eq!(ws.ctx.marks.len(), 0);
+        ws.read("~[ ]").unwrap();
+        assert_eq!(ws.ctx.pop().unwrap().to_string(), "[]");
+    }
+    
+    #[test]
+    fn comments(){
+        let mut ws = Workspace::standard();
+        assert_eq!(stack_of(&mut ws, r#"1 ( two ) 3 \ 4 5"#), vec![1, 3]);
+        ws.read(": sq ( n -- n ) dup ( copy ) * ; : cube dup sq * ; 2 cube").unwrap();
+        assert_eq!(ws.ctx.pop().unwrap().to_int().unwrap(), 8);
+        
+        // Comments continue across lines
+        ws.read(": inc ( n").unwrap();
+        assert_eq!(ws.prompt(), "(>");
+        ws.read("-- n+1 ) 1").unwrap();
+        ws.read("+ ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "4 inc"), vec![5]);
+        
+        // Only separate tokens start comments
+        let reply = ws.read(r#"[1 ( 2 ) 3] . "a\\b (c)" ."#).unwrap();
+        assert_eq!(reply[0].to_string(), "[1, 3]");
+        assert_eq!(reply[1].to_string(), "'a\\b (c)'");
+        assert_eq!(ws.read("1 )").unwrap_err().kind, ErrKind::UnknownWord);
+    }
+    
+    #[test]
+    fn quotations(){
+        let mut ws = Workspace::standard();
+        assert_eq!(stack_of(&mut ws, "3 [: 1 + ;] execute"), vec![4]);
+        assert_eq!(stack_of(&mut ws, "2 ' dup execute ['] * execute"), vec![4]);
+        
+        // Quotations keep their own branches when compiled into words
+        ws.read(": twice dup >r execute r> execute ;").unwrap();
+        ws.read(": clamp [: dup 10 > if drop 10 then ;] ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "30 clamp execute 4 clamp execute"), vec![10, 4]);
+        ws.read(": sum3 [: 0 swap 0 do i + loop ;] execute ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "4 sum3"), vec![6]);
+        assert_eq!(stack_of(&mut ws, "1 [: [: 2 * ;] twice ;] execute"), vec![4]);
+        
+        // Ticks are late bound like calls
+        ws.read(": step 1 + ;").unwrap();
+        ws.read(": stepper ['] step ;").unwrap();
+        ws.read(": step 2 + ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "0 stepper twice"), vec![4]);
+        assert_eq!(stack_of(&mut ws, "[: 5 throw ;] catch"), vec![5]);
+        
+        assert_eq!(ws.read("' nothing").unwrap_err().kind, ErrKind::UnknownWord);
+        assert_eq!(ws.read(": bad [: 1 ;").unwrap_err().kind, ErrKind::Compile);
+        assert_eq!(ws.read(";]").unwrap_err().kind, ErrKind::Compile);
+        // Quotations can't leave or exit the word around them
+        for line in [": f 10 0 do [: leave ;] drop loop ;", ": f 10 0 do 1 if [: 1 if leave then ;] then drop loop ;",
+                ": f [: exit ;] ;", ": f [: recurse ;] ;", "[: leave ;]"]{
+            assert_eq!(ws.read(line).unwrap_err().kind, ErrKind::Compile, "{}", line);
+        }
+        assert_eq!(stack_of(&mut ws, ": g [: 3 0 do i leave loop ;] execute ; g"), vec![0]);
+        assert_eq!(ws.read("5 execute").unwrap_err().kind, ErrKind::TypeMismatch);
+    }
+    
+    #[test]
+    fn list_words(){
+        let mut ws = Workspace::standard();
+        assert_eq!(top_of(&mut ws, "[1 2 3] [: dup * ;] map"), "[1, 4, 9]");
+        assert_eq!(top_of(&mut ws, "0 6 range [: 2 % ;] filter"), "[1, 3, 5]");
+        assert_eq!(top_of(&mut ws, "[1 2 3 4] ' + reduce"), "10");
+        assert_eq!(top_of(&mut ws, "[1 2 3] 10 [: - ;] fold"), "4");
+        assert_eq!(top_of(&mut ws, "[1 2 3] [4 5] zip"), "[[1, 4], [2, 5]]");
+        assert_eq!(top_of(&mut ws, "[1 2 3 4] 1 3 slice"), "[2, 3]");
+        assert_eq!(top_of(&mut ws, "[1 2] [3] concat reverse"), "[3, 2, 1]");
+        assert_eq!(top_of(&mut ws, "[3 1.5 2] sort"), "[1.5000, 2, 3]");
+        assert_eq!(top_of(&mut ws, r#"["b" "a"] sort"#), "['a', 'b']");
+        assert_eq!(top_of(&mut ws, "[[1 3] [2 1]] [: 1 access ;] sort_by"), "[[2, 1], [1, 3]]");
+        assert_eq!(top_of(&mut ws, "[4 5 6] 6 index_of"), "2");
+        assert_eq!(top_of(&mut ws, "[4 5 6] 7 index_of"), "-1");
+        
+        assert_eq!(stack_of(&mut ws, "[1 4 9] [: 3 > ;] find"), vec![4, -1]);
+        assert_eq!(stack_of(&mut ws, "[1 2] [: 3 > ;] find"), vec![0, 0]);
+        assert_eq!(stack_of(&mut ws, "0 [1 2 3] [: + ;] each"), vec![6]);
+        // Callbacks can use values under the list
+        assert_eq!(top_of(&mut ws, "10 [1 2] [: over + ;] map swap drop"), "[11, 12]");
+        
+        assert_eq!(ws.read("[1 2] [: drop ;] map").unwrap_err().kind, ErrKind::Invalid);
+        assert_eq!(ws.read("[] ' + reduce").unwrap_err().kind, ErrKind::Invalid);
+        assert_eq!(ws.read("[1 2] 1 3 slice").unwrap_err().kind, ErrKind::Index);
+        assert_eq!(ws.read(r#"[1 "a"] sort"#).unwrap_err().kind, ErrKind::TypeMismatch);
+    }
+    
+    #[test]
+    fn forms(){
+        let mut ws = Workspace::standard();
+        ws.read("10 = a").unwrap();
+        ws.read("20 = a").unwrap();
+        assert_eq!(stack_of(&mut ws, "a"), vec![20]);
+        
+        ws.read("{").unwrap();
+        ws.read(r#":var 10 :name "osc""#).unwrap();
+        ws.read(":inner { :x [1 2] } } = form").unwrap();
+        assert_eq!(stack_of(&mut ws, "form.var"), vec![10]);
+        assert_eq!(top_of(&mut ws, "form.inner.x"), "[1, 2]");
+        assert_eq!(top_of(&mut ws, "form"), "{ :var 10 :name 'osc' :inner { :x [1, 2] } }");
+        
+        ws.read("11 form.var! 5 form.inner.x!").unwrap();
+        ws.read(": bump form.var 1 + form.var! ;").unwrap();
+        assert_eq!(stack_of(&mut ws, "bump form.var form.inner.x"), vec![12, 5]);
+        
+        assert_eq!(ws.read("form.missing").unwrap_err().kind, ErrKind::Index);
+        assert_eq!(ws.read("1 form.missing!").unwrap_err().kind, ErrKind::Index);
+        assert_eq!(ws.read("form.var.x").unwrap_err().kind, ErrKind::TypeMismatch);
+        
+        // A nested form can be swapped for another form but not a scalar
+        assert_eq!(ws.read("2 form.inner!").unwrap_err().kind, ErrKind::TypeMismatch);
+        assert_eq!(top_of(&mut ws, "form.inner"), "{ :x 5 }");
+        ws.read("{ :y 3 } form.inner!").unwrap();
+        assert_eq!(stack_of(&mut ws, "form.inner.y"), vec![3]);
+        assert_eq!(ws.read("a.x").unwrap_err().kind, ErrKind::TypeMismatch);
+        assert_eq!(ws.read("nothing.x").unwrap_err().kind, ErrKind::UnknownWord);
+        assert_eq!(ws.read("{ :x 1 2 }").unwrap_err().kind, ErrKind::Parse);
+    }
+    
+    #[test]
+    fn comparisons(){
+        let mut ws = Workspace::standard();
+        assert_eq!(stack_of(&mut ws, "1 2 < 2 1 < 2 2 <= 3 2 >= 1.5 2 >"), vec![-1, 0, -1, -1, 0]);
+        assert_eq!(stack_of(&mut ws, r#""abc" "abd" < "a" "a" == "a" "b" !="#), vec![-1, -1, -1]);
+        assert_eq!(stack_of(&mut ws, "[1 [2 3]] [1 [2 3]] == [1 2] [1 2 3] < [1 2] [1 3] =="), vec![-1, -1, 0]);
+        assert_eq!(stack_of(&mut ws, r#"1 1.0 == 1 "1" == 2 2 !="#), vec![-1, 0, 0]);
+        
+        // Tolerance only applies to floats
+        assert_eq!(stack_of(&mut ws, "0.1 0.2 + 0.3 =="), vec![0]);
+        ws.read("0.000001 set_tolerance").unwrap();
+        assert_eq!(stack_of(&mut ws, "0.1 0.2 + 0.3 == [1.0 2.0] [1.0000001 2] =="), vec![-1, -1]);
+        assert_eq!(ws.read("-1.0 set_tolerance").unwrap_err().kind, ErrKind::Invalid);
+        
+        assert_eq!(stack_of(&mut ws, "0 not 5 not 0 0= -1 0 and -1 0 or 1 2 == not"), vec![-1, 0, -1, 0, -1, -1]);
+        assert_eq!(stack_of(&mut ws, "-1 -1 and 0 0 or"), vec![-1, 0]);
+        assert_eq!(ws.read(r#"1 "a" <"#).unwrap_err().kind, ErrKind::TypeMismatch);
+        
+        // A list and a single value compare element by element
+        assert_eq!(top_of(&mut ws, "[1 2 3] 2 >"), "[0, 0, -1]");
+        assert_eq!(top_of(&mut ws, r#"1 [0 [1 2]] <="#), "[0, [-1, -1]]");
+        assert_eq!(top_of(&mut ws, r#"["a" "c"] "b" <"#), "[-1, 0]");
+        ws.read(r#"3 3 == "equal" assert"#).unwrap();
+        assert_eq!(ws.read(r#"0 "zero" assert"#).unwrap_err().kind, ErrKind::Assert);
+    }
+    
+    #[test]
+    fn int_widths(){
+        let mut ws = Workspace::standard();
+        assert_eq!(stack_of(&mut ws, "300 to_u8 200 to_i8 -1 to_u16 -1 to_u32 x80000000 to_i32"),
+            vec![44, -56, 65535, 4294967295, -2147483648]);
+        assert_eq!(top_of(&mut ws, "[255 256] to_i8"), "[-1, 0]");
+        
+        // Logical and arithmetic shifts
+        assert_eq!(stack_of(&mut ws, "-16 2 arshift -1 60 rshift 1 63 lshift"), vec![-4, 15, i64::MIN]);
+        assert_eq!(stack_of(&mut ws, "-1 1 u< -1 1 <"), vec![0, -1]);
+        
+        ws.read("wrap32").unwrap();
+        assert_eq!(stack_of(&mut ws, "x7fffffff 1 + xffffffff 1 + xffffffff 0 <"),
+            vec![-2147483648, 0, -1]);
+        assert_eq!(stack_of(&mut ws, "-16 2 rshift -16 2 arshift 1 31 lshift"),
+            vec![1073741820, -4, -2147483648]);
+        assert_eq!(ws.read("1 32 lshift").unwrap_err().kind, ErrKind::Range);
+        assert_eq!(stack_of(&mut ws, "x10000 x10000 * -1 xffffffff == -1 1 u>"), vec![0, -1, -1]);
+        let reply = ws.read("-1 .x").unwrap();
+        assert_eq!(reply[0].to_string(), "'0xffffffff'");
+        
+        ws.read("wrap64").unwrap();
+        assert_eq!(stack_of(&mut ws, "x7fffffff 1 +"), vec![2147483648]);
+        assert_eq!(ws.read("x100000000 0 write").unwrap_err().kind, ErrKind::Invalid);
+    }
+    
+    #[test]
+    fn unary_math(){
+        let mut ws = Workspace::standard();
+        assert_eq!(stack_of(&mut ws, "-3 abs 4 negate 2 10 pow 3 7 min 3 7 max 12 0 10 clamp"),
+            vec![3, -4, 1024, 3, 7, 10]);
+        assert_eq!(top_of(&mut ws, "16 sqrt"), "4.0000");
+        assert_eq!(top_of(&mut ws, "[-1.5 2.5] floor"), "[-2.0000, 2.0000]");
+        assert_eq!(top_of(&mut ws, "[[1 -2] -3] abs"), "[[1, 2], 3]");
+        assert_eq!(top_of(&mut ws, "2.0 0.5 pow"), "1.4142");
+        
+        // Generators apply the operation to each sample
+        ws.read("natural negate 2 pow 1 5 clamp").unwrap();
+        let mut gen = match ws.ctx.pop(){
+            Ok(ForthVal::Generator(gen)) => gen,
+            _ => panic!("Expected generator")
+        };
+        let samples: Vec<f64> = (0..4).map(|_| gen.nextf()).collect();
+        assert_eq!(samples, vec![1.0, 1.0, 4.0, 5.0]);
+    }
+    
+    #[test]
+    fn bit_fields(){
+        let mut ws = Workspace::standard();
+        assert_eq!(stack_of(&mut ws, "b1100 b1010 xor 0 invert xff popcount"), vec![6, -1, 8]);
+        assert_eq!(stack_of(&mut ws, "xabcd 11 8 bits xabcd 0 0 bits -1 63 60 bits"), vec![0xb, 1, 15]);
+        assert_eq!(stack_of(&mut ws, "xabcd 5 11 8 set_bits 0 -1 7 4 set_bits"), vec![0xa5cd, 0xf0]);
+        assert_eq!(stack_of(&mut ws, "xff 8 sign_extend x7f 8 sign_extend x800 12 sign_extend"),
+            vec![-1, 127, -2048]);
+        assert_eq!(top_of(&mut ws, "[1 3 7] popcount"), "[1, 2, 3]");
+        assert_eq!(ws.read("1 3 4 bits").unwrap_err().kind, ErrKind::Invalid);
+        
+        ws.read("wrap32").unwrap();
+        assert_eq!(stack_of(&mut ws, "-1 popcount 0 1 31 31 set_bits"), vec![32, -2147483648]);
+    }
+    
+    #[test]
+    fn checked_arithmetic(){
+        let mut ws = Workspace::standard();
+        for (line, kind) in [("1 0 /", ErrKind::DivideByZero), ("1 0 %", ErrKind::DivideByZero),
+                ("x7fffffffffffffff 1 +", ErrKind::Overflow), ("x4000000000000000 2 *", ErrKind::Overflow),
+                ("x8000000000000000 -1 /", ErrKind::Overflow), ("x8000000000000000 negate", ErrKind::Overflow),
+                ("2 64 pow", ErrKind::Overflow), ("[1 2] 0 /", ErrKind::DivideByZero),
+                ("1 64 lshift", ErrKind::Range), ("1 -1 lshift", ErrKind::Range), ("-1 64 rshift", ErrKind::Range),
+                ("1.0 64 arshift", ErrKind::Range), ("[1 2] 70 lshift", ErrKind::Range)]{
+            assert_eq!(ws.read(line).unwrap_err().kind, kind, "{}", line);
+        }
+        assert_eq!(stack_of(&mut ws, "1 0 ' / catch nip nip 1 64 ' lshift catch nip nip"), vec![-10, -24]);
+        assert_eq!(top_of(&mut ws, "1.0 0.0 /"), "inf");
+        
+        // 32 bit mode wraps instead
+        ws.read("wrap32").unwrap();
+        assert_eq!(stack_of(&mut ws, "x7fffffff 1 + x80000000 -1 /"), vec![-2147483648, -2147483648]);
+        ws.read("wrap64").unwrap();
+        
+        // Big ints are made with to_big or an n suffix
+        assert_eq!(top_of(&mut ws, "x7fffffffffffffff to_big 1 +"), "9223372036854775808n");
+        assert_eq!(top_of(&mut ws, "1n 100 lshift 1 -"), "1267650600228229401496703205375n");
+        assert_eq!(top_of(&mut ws, "-xffff_ffff_ffff_ffff_ffffn 16 arshift"), "-18446744073709551616n");
+        assert_eq!(top_of(&mut ws, "[1 2] 10n *"), "[10n, 20n]");
+        assert_eq!(stack_of(&mut ws, "5n 5 == 1n 100 lshift 1 > 40n 2 / to_int 7n 2 %"), vec![-1, -1, 20, 1]);
+        assert_eq!(ws.read("1n 0 /").unwrap_err().kind, ErrKind::DivideByZero);
+        assert_eq!(ws.read("1n 100 lshift to_int").unwrap_err().kind, ErrKind::Overflow);
+        assert_eq!(ws.read("1n 2 rshift").unwrap_err().kind, ErrKind::TypeMismatch);
+        let reply = ws.read("1n 64 lshift .x").unwrap();
+        assert_eq!(reply[0].to_string(), "'0x10000000000000000'");
+    }
+    
+    /// Pop a generator and take n samples
+    fn samples_of(ws: &mut Workspace, n: usize) -> Vec<f64>{
+        let mut gen = match ws.ctx.pop(){
+            Ok(ForthVal::Generator(gen)) => gen,
+            _ => panic!("Expected generator")
+        };
+        (0..n).map(|_| (gen.nextf() * 1e6).round() / 1e6).collect()
+    }
+    
+    #[test]
+    fn oscillators(){
+        let mut ws = Workspace::standard();
+        ws.read("8 set_sample_rate").unwrap();
+        ws.read("2 3 0 sine").unwrap();
+        assert_eq!(samples_of(&mut ws, 5), vec![0.0, 3.0, 0.0, -3.0, 0.0]);
+        ws.read("1 1 0 saw").unwrap();
+        assert_eq!(samples_of(&mut ws, 3), vec![-1.0, -0.75, -0.5]);
+        ws.read("2 1 0 triangle").unwrap();
+        assert_eq!(samples_of(&mut ws, 5), vec![0.0, 1.0, 0.0, -1.0, 0.0]);
+        ws.read("2 1 0.5 square").unwrap();
+        assert_eq!(samples_of(&mut ws, 4), vec![-1.0, -1.0, 1.0, 1.0]);
+        ws.read("1 1 0 0.25 pulse").unwrap();
+        assert_eq!(samples_of(&mut ws, 4), vec![1.0, 1.0, -1.0, -1.0]);
+        ws.read("3 ramp").unwrap();
+        assert_eq!(samples_of(&mut ws, 4), vec![0.0, 1.0, 2.0, 0.0]);
+        
+        // Noise stays within the amplitude
+        ws.read("0.5 white_noise").unwrap();
+        assert!(samples_of(&mut ws, 1000).iter().all(|s| s.abs() <= 0.5));
+        ws.read("1 pink_noise").unwrap();
+        assert!(samples_of(&mut ws, 1000).iter().all(|s| s.abs() <= 1.0));
+        
+        assert_eq!(ws.read("1 \"loud\" 0 sine").unwrap_err().kind, ErrKind::TypeMismatch);
+        assert_eq!(ws.read("0 set_sample_rate").unwrap_err().kind, ErrKind::Invalid);
+    }
+    
+    #[test]
+    fn generator_arguments(){
+        let mut ws = Workspace::standard();
+        ws.read("8 set_sample_rate").unwrap();
+        // Amplitude, frequency and pulse width follow other generators sample by sample
+        ws.read("2 4 ramp 0 square").unwrap();
+        assert_eq!(samples_of(&mut ws, 8), vec![0.0, 1.0, -2.0, -3.0, 0.0, 1.0, -2.0, -3.0]);
+        ws.read("4 ramp 1 0 saw").unwrap();
+        assert_eq!(samples_of(&mut ws, 5), vec![-1.0, -1.0, -0.75, -0.25, 0.5]);
+        ws.read("1 1 0 2 ramp 0.5 * pulse").unwrap();
+        assert_eq!(samples_of(&mut ws, 4), vec![-1.0, 1.0, -1.0, 1.0]);
+        
+        // Printing a copy leaves the generator and its arguments in step
+        ws.read("48000 set_sample_rate 440 5 1 0 sine 10 * + 1 0 sine dup .").unwrap();
+        let copy = samples_of(&mut ws, 9);
+        ws.read("440 5 1 0 sine 10 * + 1 0 sine").unwrap();
+        assert_eq!(samples_of(&mut ws, 9), copy);
+        assert!(copy.iter().all(|s| s.abs() <= 1.0));
+        
+        assert_eq!(ws.read("\"fast\" 1 0 sine").unwrap_err().kind, ErrKind::TypeMismatch);
+        // A bad argument leaves all of them on the stack
+        ws.ctx.stack.clear();
+        assert_eq!(ws.read("440 \"loud\" 0 sine").unwrap_err().kind, ErrKind::TypeMismatch);
+        assert_eq!(ws.ctx.stack.len(), 3);
+    }
+    
+    #[test]
+    fn generator_combinators(){
+        let mut ws = Workspace::standard();
+        // Finite generators are collected and printed whole
+        assert_eq!(top_of(&mut ws, "natural 3 take collect"), "[0.0000, 1.0000, 2.0000]");
+        assert_eq!(top_of(&mut ws, "2 natural 5 skip collect"), "[5.0000, 6.0000]");
+        assert_eq!(top_of(&mut ws, "natural 2 take"), "{0.0000, 1.0000}");
+        assert_eq!(stack_of(&mut ws, "natural 10 take 4 skip 3 decimate len"), vec![2]);
+        
+        ws.read("natural 3 take [7 8] cycle concat").unwrap();
+        assert_eq!(samples_of(&mut ws, 6), vec![0.0, 1.0, 2.0, 7.0, 8.0, 7.0]);
+        ws.read("natural [10 20] cycle zip").unwrap();
+        assert_eq!(samples_of(&mut ws, 5), vec![0.0, 10.0, 1.0, 20.0, 2.0]);
+        ws.read("natural natural 2 * mix").unwrap();
+        assert_eq!(samples_of(&mut ws, 3), vec![0.0, 3.0, 6.0]);
+        ws.read("natural natural 1 + *").unwrap();
+        assert_eq!(samples_of(&mut ws, 3), vec![0.0, 2.0, 6.0]);
+        ws.read("natural 3 <").unwrap();
+        assert_eq!(samples_of(&mut ws, 4), vec![-1.0, -1.0, -1.0, 0.0]);
+        ws.read("natural accumulate").unwrap();
+        assert_eq!(samples_of(&mut ws, 4), vec![0.0, 1.0, 3.0, 6.0]);
+        ws.read("[0 4] cycle 2 window").unwrap();
+        assert_eq!(samples_of(&mut ws, 3), vec![0.0, 2.0, 2.0]);
+        
+        // Combinators of finite generators end with their sources
+        assert_eq!(stack_of(&mut ws, "natural 3 take natural 5 take zip len"), vec![6]);
+        ws.read("natural 2 take").unwrap();
+        let samples = samples_of(&mut ws, 3);
+        assert!(samples[2].is_nan());
+        assert_eq!(ws.read("natural natural concat").unwrap_err().kind, ErrKind::Invalid);
+        assert_eq!(ws.read("[] cycle").unwrap_err().kind, ErrKind::Invalid);
+        
+        // Bad arguments leave the stack as it was
+        ws.ctx.stack.clear();
+        for (line, kind) in [("natural 0 decimate", ErrKind::Invalid), ("natural 0 window", ErrKind::Invalid),
+                ("natural -1 take", ErrKind::Index), ("[1] 2 skip", ErrKind::TypeMismatch),
+                ("natural \"a\" take", ErrKind::TypeMismatch), ("-1 natural collect", ErrKind::Index),
+                ("1 [1] collect", ErrKind::TypeMismatch)]{
+            assert_eq!(ws.read(line).unwrap_err().kind, kind, "{}", line);
+            assert_eq!(ws.ctx.stack.len(), 2, "{}", line);
+            ws.ctx.stack.clear();
+        }
+        
+        // Skipped samples are only dropped when they are needed
+        ws.read("natural 1000000000000 skip 3 take len").unwrap();
+        assert_eq!(ws.ctx.pop_int().unwrap(), 3);
+        ws.read("natural 5 take 3 skip").unwrap();
+        assert_eq!(samples_of(&mut ws, 3)[..2], [3.0, 4.0]);
+    }
+    
+    #[test]
+    fn generator_blocks(){
+        let mut ws = Workspace::standard();
+        ws.read("8 set_sample_rate").unwrap();
+        // Constants keep their order and are applied to the float samples
+        ws.read("5 natural -").unwrap();
+        assert_eq!(samples_of(&mut ws, 3), vec![5.0, 4.0, 3.0]);
+        ws.read("1 1 0 sine 10 *").unwrap();
+        assert_eq!(samples_of(&mut ws, 2), vec![0.0, 7.071068]);
+        assert_eq!(stack_of(&mut ws, "natural [1 2] + len"), vec![2]);
+        
+        // Blocks give the same samples as single steps, and NaN after the end
+        ws.read("1 1 0 sine 2 ramp * 3 + abs natural 6 take mix dup").unwrap();
+        let mut single = match ws.ctx.pop(){
+            Ok(ForthVal::Generator(gen)) => gen,
+            _ => panic!("Expected generator")
+        };
+        let mut block = match ws.ctx.pop(){
+            Ok(ForthVal::Generator(gen)) => gen,
+            _ => panic!("Expected generator")
+        };
+        let mut samples = [0.0; 8];
+        block.fill(&mut samples[..3]);
+        block.fill(&mut samples[3..]);
+        for x in samples{
+            let y = single.nextf();
+            assert!(x == y || (x.is_nan() && y.is_nan()));
+        }
+        assert!(samples[5].is_finite() && samples[6].is_nan());
+    }
+    
+    // Benchmarks, run with cargo test --release -- --ignored --nocapture
+    fn bench(name: &str, n: u32, mut f: impl FnMut()){
+        let start = std::time::Instant::now();
+        for _ in 0..n{
+            f();
+        }
+        println!("{}: {:?} per iteration", name, start.elapsed() / n);
+    }
+    
+    #[test]
+    #[ignore]
+    fn bench_generator_pipeline(){
+        let mut ws = Workspace::standard();
+        ws.read("natural 5 + 3 * 2 -").unwrap();
+        let mut gen = match ws.ctx.pop(){
+            Ok(ForthVal::Generator(gen)) => gen,
+            _ => panic!("Expected generator")
+        };
+        bench("generator pipeline", 1_000_000, || {gen.nextf();});
+    }
+    
+    #[test]
+    #[ignore]
+    fn bench_generator_block(){
+        let mut ws = Workspace::standard();
+        // Ten operators on a sine, one second at 48 kHz in audio sized blocks
+        ws.read("48000 set_sample_rate 440 1 0 sine 2 * 1 + 0.5 * abs 3 min 0.1 - 2 / negate 1 + 0.9 *").unwrap();
+        let mut gen = match ws.ctx.pop(){
+            Ok(ForthVal::Generator(gen)) => gen,
+            _ => panic!("Expected generator")
+        };
+        let mut block = [0.0; 512];
+        let start = std::time::Instant::now();
+        for _ in 0..48000 / block.len(){
+            gen.fill(&mut block);
+        }
+        let elapsed = start.elapsed();
+        println!("generator block: {:?} per second of audio, {:.0}x real time", elapsed, 1.0 / elapsed.as_secs_f64());
+        assert!(elapsed.as_secs_f64() < 1.0);
+    }
+    
+    #[test]
+    #[ignore]
+    fn bench_compiled_words(){
+        let mut ws = Workspace::standard();
+        ws.read(": sq dup * ;").unwrap();
+        ws.read(": sum 0 100 0 do i sq + loop ;").unwrap();
+        bench("compiled words", 10_000, || {ws.read("sum").unwrap(); ws.ctx.stack.clear();});
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{drivers::Serial, types::ForthVal};
+use crate::{drivers::Serial, types::{ErrKind, ForthErr, ForthVal}};
 use crate::drivers::DeviceInterface;
 
 #[derive(Clone)]
@@ -96,6 +96,16 @@ impl Stack{
         }
     }
     
+    pub fn truncate(&mut self, n: usize) -> Result<(), ForthErr>{
+        if self.local{
+            self.stack.truncate(n);
+            Ok(())
+        }
+        else{
+            Err(ForthErr::new(ErrKind::Device, "can't truncate the client stack"))
+        }
+    }
+    
     pub fn get(&self, i: usize) -> Option<&ForthVal>{
         if self.local{
             self.stack.get(i)
@@ -114,6 +124,15 @@ impl Stack{
         }
     }
     
+    pub fn last_mut(&mut self) -> Result<Option<&mut ForthVal>, ForthErr>{
+        if self.local{
+            Ok(self.stack.last_mut())
+        }
+        else{
+            Err(ForthErr::new(ErrKind::Device, "can't change values on the client stack"))
+        }
+    }
+    
     pub fn get_local(&self) -> &Vec<ForthVal>{
         &self.stack
     }
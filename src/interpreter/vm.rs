@@ -0,0 +1,262 @@
+/*********************************/
+/* Inner interpreter *************/
+/* Compiled words are a stream   */
+/* of instructions, run with an  */
+/* instruction pointer           */
+/*********************************/
+use std::rc::Rc;
+
+use crate::types::{ErrKind, ForthErr, ForthVal, Flow};
+use super::{ForthRoutine, Workspace, WorkspaceContext, MAX_NESTING};
+use super::control::LoopFrame;
+
+/// Instruction for compiled words
+#[derive(Clone)]
+pub enum Op{
+    // Call word by id
+    Call(usize),
+    // Push a value
+    Lit(ForthVal),
+    // Branches and loops
+    Flow(Flow),
+    // Return from the word
+    Exit,
+    // Call the word being run
+    Recurse,
+    // Value which needs the outer interpreter
+    Token(ForthVal)
+}
+
+impl From<&ForthVal> for Op{
+    fn from(v: &ForthVal) -> Self{
+        match v{
+            ForthVal::Func(id) => Op::Call(*id),
+            ForthVal::Flow(Flow::Exit) => Op::Exit,
+            ForthVal::Flow(Flow::Recurse) => Op::Recurse,
+            ForthVal::Flow(Flow::Tick(id)) => Op::Lit(ForthVal::Func(*id)),
+            ForthVal::Flow(flow) => Op::Flow(*flow),
+            ForthVal::Sym(_) | ForthVal::Meta(_) | ForthVal::Property(_) => {
+                Op::Token(v.clone())
+            },
+            _ => Op::Lit(v.clone())
+        }
+    }
+}
+
+/// Assemble compiled tokens into a program
+/// Each token becomes one instruction so branch targets are unchanged
+pub fn assemble(built: &[ForthVal]) -> Rc<[Op]>{
+    built.iter().map(Op::from).collect()
+}
+
+/// Compiled word being run
+pub struct Frame{
+    program: Rc<[Op]>,
+    // Instruction to continue from after a call
+    ip: usize,
+    // Anonymous programs have no id
+    id: Option<usize>,
+    // Depth of return stack and loops on entry
+    rdepth: usize,
+    loops: usize
+}
+
+/// Push the result of a builtin
+fn push_result(ctx: &mut WorkspaceContext, result: ForthVal){
+    match result{
+        ForthVal::Null => (),
+        ForthVal::Vector(values) => {
+            for v in values{
+                ctx.push(v);
+            }
+        },
+        _ => ctx.push(result)
+    }
+}
+
+impl Workspace{
+    /// Call word by id, errors are tagged with the name of the word
+    pub fn call(&mut self, id: usize) -> Result<(), ForthErr>{
+        let routine = match self.ctx.dictionary.get_fn_from_id(&id){
+            Some(routine) => routine.clone(),
+            None => {return Err(ForthErr::new(ErrKind::UnknownWord, format!("id {}", id)));}
+        };
+        match routine{
+            ForthRoutine::Compiled(program) => self.run_program(program, Some(id)),
+            _ => {
+                self.run_routine(&routine)
+                    .map_err(|e| e.in_word(self.ctx.dictionary.get_name(&id)))
+            }
+        }
+    }
+
+    /// Run a routine
+    pub fn run_routine(&mut self, routine: &ForthRoutine) -> Result<(), ForthErr>{
+        match routine{
+            ForthRoutine::Prim(f) => {
+                let result = f(&mut self.ctx)?;
+                push_result(&mut self.ctx, result);
+            },
+            ForthRoutine::Compiled(program) => self.run_program(program.clone(), None)?,
+            ForthRoutine::Exec(f) => f(self)?
+        };
+        Ok(())
+    }
+
+    /// Run compiled program until it returns
+    /// Errors are traced through the words that were running
+    pub fn run_program(&mut self, program: Rc<[Op]>, id: Option<usize>) -> Result<(), ForthErr>{
+        if self.nesting >= MAX_NESTING{
+            return Err(ForthErr::new(ErrKind::CallDepth,
+                format!("more than {} nested programs", MAX_NESTING)));
+        }
+        let base = self.frames.len();
+        self.nesting += 1;
+        let result = self.enter(program, id).and_then(|_| self.run_frames(base));
+        self.nesting -= 1;
+        if let Err(mut e) = result{
+            while self.frames.len() > base{
+                if let Some(Frame{id: Some(id), ..}) = self.frames.pop(){
+                    e = e.called_from(self.ctx.dictionary.get_name(&id));
+                }
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Start running a compiled word
+    fn enter(&mut self, program: Rc<[Op]>, id: Option<usize>) -> Result<(), ForthErr>{
+        if self.frames.len() >= self.ctx.max_depth{
+            return Err(ForthErr::new(ErrKind::CallDepth, 
+                format!("more than {} nested words", self.ctx.max_depth)));
+        }
+        self.frames.push(Frame{
+            program: program,
+            ip: 0,
+            id: id,
+            rdepth: self.ctx.rstack.len(),
+            loops: self.ctx.loops.len()
+        });
+        Ok(())
+    }
+
+    /// Return from the current word, the return stack must be balanced
+    fn leave(&mut self) -> Result<(), ForthErr>{
+        if let Some(frame) = self.frames.last(){
+            if self.ctx.rstack.len() != frame.rdepth{
+                self.ctx.rstack.truncate(frame.rdepth)?;
+                return Err(ForthErr::new(ErrKind::ReturnStack, "unbalanced return stack"));
+            }
+            // Exiting from inside a loop drops its index
+            self.ctx.loops.truncate(frame.loops);
+        }
+        self.frames.pop();
+        Ok(())
+    }
+
+    /// Run frames above base, following branches
+    /// Compiled words are entered without recursion
+    fn run_frames(&mut self, base: usize) -> Result<(), ForthErr>{
+        let (mut program, mut ip) = match self.frames.last(){
+            Some(frame) => (frame.program.clone(), frame.ip),
+            None => {return Ok(());}
+        };
+        loop{
+            match program.get(ip){
+                None | Some(Op::Exit) => {
+                    self.leave()?;
+                    match self.frames.last(){
+                        Some(frame) if self.frames.len() > base => {
+                            program = frame.program.clone();
+                            ip = frame.ip;
+                        },
+                        _ => {return Ok(());}
+                    }
+                    continue;
+                },
+                Some(Op::Call(id)) => {
+                    let id = *id;
+                    if let Some(ForthRoutine::Compiled(callee)) = self.ctx.dictionary.get_fn_from_id(&id){
+                        let callee = callee.clone();
+                        self.enter_from(&mut program, &mut ip, callee, Some(id))?;
+                        continue;
+                    }
+                    self.call(id)?;
+                },
+                Some(Op::Recurse) => {
+                    let id = self.frames.last().and_then(|frame| frame.id);
+                    let callee = program.clone();
+                    self.enter_from(&mut program, &mut ip, callee, id)?;
+                    continue;
+                },
+                Some(Op::Lit(v)) => self.ctx.push(v.clone()),
+                Some(Op::Flow(Flow::Jump(target))) => {
+                    ip = *target;
+                    continue;
+                },
+                Some(Op::Flow(Flow::JumpIfZero(target))) => {
+                    if self.ctx.pop_int()? == 0{
+                        ip = *target;
+                        continue;
+                    }
+                },
+                Some(Op::Flow(Flow::Do)) => {
+                    let index = self.ctx.pop_int()?;
+                    let limit = self.ctx.pop_int()?;
+                    self.ctx.loops.push(LoopFrame{index, limit});
+                },
+                Some(Op::Flow(Flow::QDo(target))) => {
+                    let index = self.ctx.pop_int()?;
+                    let limit = self.ctx.pop_int()?;
+                    if index == limit{
+                        ip = *target;
+                        continue;
+                    }
+                    self.ctx.loops.push(LoopFrame{index, limit});
+                },
+                Some(Op::Flow(flow @ Flow::Loop(target))) | Some(Op::Flow(flow @ Flow::PlusLoop(target))) => {
+                    let n = match flow{
+                        Flow::PlusLoop(_) => self.ctx.pop_int()?,
+                        _ => 1
+                    };
+                    let done = match self.ctx.loops.last_mut(){
+                        Some(frame) => frame.step(n),
+                        None => {return Err(ForthErr::new(ErrKind::Compile, "Loop without index"));}
+                    };
+                    if done{
+                        self.ctx.loops.pop();
+                    }
+                    else{
+                        ip = *target;
+                        continue;
+                    }
+                },
+                Some(Op::Flow(Flow::Leave(target))) => {
+                    self.ctx.loops.pop();
+                    ip = *target;
+                    continue;
+                },
+                Some(Op::Flow(Flow::Exit)) | Some(Op::Flow(Flow::Recurse)) | Some(Op::Flow(Flow::Tick(_))) => {
+                    unreachable!("Assembled as instructions")
+                },
+                Some(Op::Token(v)) => {
+                    let v = v.clone();
+                    self.run(&v)?
+                }
+            }
+            ip += 1;
+        }
+    }
+
+    /// Save position in the current word and start running callee
+    fn enter_from(&mut self, program: &mut Rc<[Op]>, ip: &mut usize, callee: Rc<[Op]>, id: Option<usize>) -> Result<(), ForthErr>{
+        if let Some(frame) = self.frames.last_mut(){
+            frame.ip = *ip + 1;
+        }
+        self.enter(callee.clone(), id)?;
+        *program = callee;
+        *ip = 0;
+        Ok(())
+    }
+}
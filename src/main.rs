@@ -14,7 +14,8 @@ mod reg;
 mod asm;
 mod visual;
 
-use types::ForthErr;
+use interpreter::print_reply;
+
 
 fn main(){
     println!("__welcome__");
@@ -45,21 +46,11 @@ fn main(){
                     match ctx.read(line.as_str()){
                         Ok(reply) => {
                             if reply.len() > 0{
-                                for r in reply{
-                                    print!("{} ", r.to_string());
-                                }
-                                print!("\n");
+                                print_reply(&reply);
                             }
                         },
                         Err(err) => {
-                            match err{
-                                ForthErr::ErrString(s) => {
-                                    println!("Error: {:?}", s);
-                                },
-                                ForthErr::ErrForthVal(v) => {
-                                    println!("Error on value: {:?}", v);
-                                }
-                            }
+                            println!("Error: {}", err);
                         }
                     }
                 }
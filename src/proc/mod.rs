@@ -1,5 +1,7 @@
+use std::fmt;
+
 use crate::reg;
-use crate::types::ForthVal;
+use crate::types::{ErrKind, ForthErr, ForthVal};
 
 const JUMP: u32 = 0;
 // Core data structure
@@ -47,6 +49,16 @@ impl Default for Proc{
     }
 }
 
+impl fmt::Display for Proc{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "{{")?;
+        for (name, v) in self.values.iter().filter(|(n, _)| !n.starts_with('_')){
+            write!(f, " :{} {}", name, v.to_string())?;
+        }
+        write!(f, " }}")
+    }
+}
+
 impl Proc{
     pub fn new() -> Self{
         Self{
@@ -55,6 +67,50 @@ impl Proc{
         }
     }
     
+    /// Get a field
+    pub fn get(&self, name: &str) -> Option<&ForthVal>{
+        self.values.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+    }
+    
+    /// Set a field, adding it if it is new
+    pub fn set(&mut self, name: &str, v: ForthVal){
+        match self.values.iter_mut().find(|(n, _)| n == name){
+            Some((_, old)) => *old = v,
+            None => self.values.push((name.to_string(), v))
+        }
+    }
+    
+    /// Get a field of nested forms, with names separated by .
+    pub fn get_path(&self, path: &str) -> Result<&ForthVal, ForthErr>{
+        let (name, rest) = split_path(path);
+        let v = self.get(name)
+            .ok_or_else(|| ForthErr::new(ErrKind::Index, format!("No field {} in form", name)))?;
+        match (rest, v){
+            (None, _) => Ok(v),
+            (Some(rest), ForthVal::Form(inner)) => inner.get_path(rest),
+            (Some(_), _) => Err(ForthErr::mismatch("form", v))
+        }
+    }
+    
+    /// Update an existing field of nested forms
+    /// A field holding a form can only be replaced by another form,
+    /// so a store can't silently drop its sub-fields
+    pub fn set_path(&mut self, path: &str, v: ForthVal) -> Result<(), ForthErr>{
+        let (name, rest) = split_path(path);
+        let field = self.values.iter_mut().find(|(n, _)| n == name).map(|(_, v)| v)
+            .ok_or_else(|| ForthErr::new(ErrKind::Index, format!("No field {} in form", name)))?;
+        match (rest, field){
+            (None, ForthVal::Form(_)) if !matches!(v, ForthVal::Form(_)) => Err(ForthErr::mismatch("form", &v)),
+            (None, field) => {
+                *field = v;
+                Ok(())
+            },
+            (Some(rest), ForthVal::Form(inner)) => inner.set_path(rest, v),
+            (Some(_), field) => Err(ForthErr::mismatch("form", field))
+        }
+    }
+    
+    /// Show fields, internal fields starting with _ are hidden
     pub fn append_program_as_u32(&mut self, i: u32){
         self.main.push(Instruction{op: i});
     }
@@ -94,4 +150,11 @@ impl Proc{
         println!("Local size is {}", self.local_size());
         Instruction::jal(R::t1, (self.local_size()+1) << 2)
     }
-}
\ No newline at end of file
+}
+/// Split the first name from a path of fields
+fn split_path(path: &str) -> (&str, Option<&str>){
+    match path.split_once('.'){
+        Some((name, rest)) => (name, Some(rest)),
+        None => (path, None)
+    }
+}
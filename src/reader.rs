@@ -3,8 +3,9 @@ use std::{fs::File, io::{self, BufRead}, path::Path};
 /*
 Read in lines and compile/run
 */
-use crate::types::{ForthErr, ForthRet, ForthVal};
+use crate::types::{ErrKind, ForthErr, ForthRet, ForthVal};
 
+use num_bigint::BigInt;
 use regex::Regex;
 use lazy_static::lazy_static;
 
@@ -21,47 +22,201 @@ fn tokenize(str: &str) -> Vec<String>{
     // TODO: make static
     lazy_static!{
         static ref TokenRegex: Regex = Regex::new(
-                r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
+                r###"[\s,]*(~@|'(?:\\x[0-9A-Fa-f]{2}|\\.|[^\\'\t\n])'|'\S*'|[\[\]{}()'`~^@;]|"(?:\\.|[^\\"])*"?|[^\s\[\]{}('"`,;)]+)"###
         ).unwrap();
     }
     
-    let mut res = vec![];
+    let mut res: Vec<String> = vec![];
+    let mut end = 0;
     
     for cap in TokenRegex.captures_iter(str){
-        res.push(String::from(&cap[1]))
+        let token = cap.get(1).unwrap();
+        let text = token.as_str();
+        
+        // Parsing words such as abort" take the string directly after them
+        // The string is read first so the word can take it from the stack
+        let attached = token.start() == end && text.starts_with('"');
+        // b"..." is a byte string literal rather than a parsing word
+        if attached && res.last().is_some_and(|w| w == "b"){
+            res.pop();
+            res.push(format!("b{}", text));
+            end = token.end();
+            continue;
+        }
+        let parsing = res.last()
+            .and_then(|w| w.chars().next())
+            .is_some_and(|c| !"[]{}()'`~^@\";".contains(c));
+        if attached && parsing{
+            let word = res.pop().unwrap();
+            // Single space after the word is a separator
+            res.push(text.replacen("\" ", "\"", 1));
+            res.push(format!("{}\"", word));
+        }
+        else{
+            res.push(String::from(text));
+        }
+        end = token.end();
     }
     res
 }
 
+/// Remove ( ... ) and \ comments from tokens
+/// open is true if a ( comment continues from the last line
+/// Returns the tokens and whether a ( comment is still open
+fn strip_comments(tokens: Vec<String>, mut open: bool) -> (Vec<String>, bool){
+    let mut res = Vec::new();
+    for token in tokens{
+        if open{
+            open = token != ")";
+        }
+        else if token == "("{
+            open = true;
+        }
+        else if token == "\\"{
+            break;
+        }
+        else{
+            res.push(token);
+        }
+    }
+    (res, open)
+}
+
+/// Replace escape sequences, giving bytes
+/// Text is kept as UTF-8 and \xNN gives the byte NN
+fn unescape_bytes(s: &str) -> Result<Vec<u8>, ForthErr>{
+    let mut result = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next(){
+        if c != '\\'{
+            result.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+        match chars.next(){
+            Some('n') => result.push(b'\n'),
+            Some('t') => result.push(b'\t'),
+            Some('r') => result.push(b'\r'),
+            Some('0') => result.push(0),
+            Some('\\') => result.push(b'\\'),
+            Some('"') => result.push(b'"'),
+            Some('\'') => result.push(b'\''),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16){
+                    Ok(b) if hex.len() == 2 => result.push(b),
+                    _ => {return Err(ForthErr::new(ErrKind::Parse, format!("Invalid escape \\x{}", hex)));}
+                }
+            },
+            Some(e) => {return Err(ForthErr::new(ErrKind::Parse, format!("Unknown escape \\{}", e)));},
+            None => {return Err(ForthErr::new(ErrKind::Parse, "String ends with \\"));}
+        }
+    }
+    Ok(result)
+}
+
+/// Replace escape sequences in a string, which must stay valid UTF-8
+fn unescape(s: &str) -> Result<String, ForthErr>{
+    String::from_utf8(unescape_bytes(s)?)
+        .map_err(|_| ForthErr::new(ErrKind::Parse, "String is not UTF-8, use b\"...\" for raw bytes"))
+}
+
+/// Parse a number, returns None if the token is not a number
+/// Accepts 0x/x, 0b/b and 0o prefixes, _ between digits and floats in base 10
+/// Prefixes are only used when their letter is not a digit in base
+/// An n suffix gives a big int, as in 1_000_000_000_000_000_000_000n
+pub fn parse_number(token: &str, base: u32) -> Result<Option<ForthVal>, ForthErr>{
+    lazy_static!{
+        static ref FLOAT_RE: Regex = Regex::new(
+            r"^[0-9]+(_[0-9]+)*(\.[0-9]+(_[0-9]+)*)?([eE][+-]?[0-9]+)?$"
+        ).unwrap();
+    }
+    
+    let (negative, body) = match token.strip_prefix('-'){
+        Some(rest) => (true, rest),
+        None => (false, token)
+    };
+    
+    let mut radix = base;
+    let mut digits = body;
+    for (prefix, prefix_base) in [("0x", 16), ("x", 16), ("0b", 2), ("b", 2), ("0o", 8)]{
+        let letter = prefix.chars().last().unwrap();
+        if let Some(rest) = body.strip_prefix(prefix){
+            if !letter.is_digit(base){
+                radix = prefix_base;
+                digits = rest;
+                break;
+            }
+        }
+    }
+    
+    let big = digits.len() > 1 && digits.ends_with('n') && !'n'.is_digit(radix);
+    if big{
+        digits = &digits[..digits.len() - 1];
+    }
+    
+    let valid = digits.chars().next().is_some_and(|c| c.is_digit(radix))
+        && digits.chars().last().is_some_and(|c| c.is_digit(radix))
+        && digits.chars().all(|c| c == '_' || c.is_digit(radix));
+    if !valid{
+        if radix == 10 && digits.len() == body.len() && FLOAT_RE.is_match(body){
+            // Integers are matched above, so this has a point or exponent
+            let f: f64 = body.replace('_', "").parse()
+                .map_err(|_| ForthErr::new(ErrKind::Parse, format!("Invalid float {}", token)))?;
+            return Ok(Some(ForthVal::Float(if negative {-f} else {f})));
+        }
+        return Ok(None);
+    }
+    
+    if big{
+        let v = BigInt::parse_bytes(digits.replace('_', "").as_bytes(), radix)
+            .ok_or_else(|| ForthErr::new(ErrKind::Parse, format!("Invalid number {}", token)))?;
+        return Ok(Some(ForthVal::Big(if negative {-v} else {v})));
+    }
+    
+    let out_of_range = || ForthErr::new(ErrKind::Parse, format!("Number out of range {}", token));
+    let v = u64::from_str_radix(&digits.replace('_', ""), radix).map_err(|_| out_of_range())?;
+    // Other bases can give any 64 bit pattern
+    if radix == 10 && v > i64::MAX as u64 + negative as u64{
+        return Err(out_of_range());
+    }
+    let v = v as i64;
+    Ok(Some(ForthVal::Int(if negative {v.wrapping_neg()} else {v})))
+}
+
 /// Read word in
-fn read_atom(token: &String) -> ForthRet{
+fn read_atom(token: &String, base: u32) -> ForthRet{
     lazy_static!{
-        static ref INT_RE: Regex = Regex::new(r"^-?[0-9]+$").unwrap();
-        static ref HEX_RE: Regex = Regex::new(r"x[0-9|A-F|a-f]+$").unwrap();
-        static ref BIN_RE: Regex = Regex::new(r"b[0-1]+$").unwrap();
-        static ref FLOAT_RE: Regex = Regex::new(r"^-?[0-9]+.[0-9]+$").unwrap();
         static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*""#).unwrap();
     }
     
-    if INT_RE.is_match(&token){
-        Ok(ForthVal::Int(token.parse().unwrap()))
-    }
-    else if HEX_RE.is_match(&token){
-        // Convert from hex
-        let wo_prefix= token.trim_start_matches("x");
-        Ok(ForthVal::Int(i64::from_str_radix(wo_prefix, 16).unwrap()))
+    // Letters can be digits in large bases, these are read as words
+    // and only used as numbers when there is no such word
+    if base <= 10 || !token.chars().any(|c| c.is_alphabetic()){
+        if let Some(v) = parse_number(token, base)?{
+            return Ok(v);
+        }
     }
-    else if BIN_RE.is_match(&token){
-        let wo_prefix = token.trim_start_matches("b");
-        Ok(ForthVal::Int(i64::from_str_radix(wo_prefix, 2).unwrap()))
+    
+    if token.len() >= 2 && token.starts_with('\'') && token.ends_with('\''){
+        // Character literal, \xNN gives the code NN and \' a quote
+        let inner = &token[1..token.len()-1];
+        if inner.replace("\\\\", "").replace("\\'", "").contains('\''){
+            return Err(ForthErr::new(ErrKind::Parse, format!("Invalid character {}, use '\\''", token)));
+        }
+        let bytes = unescape_bytes(inner)?;
+        let s = std::str::from_utf8(&bytes).unwrap_or("");
+        let mut chars = s.chars();
+        match (bytes.as_slice(), chars.next(), chars.next()){
+            ([b], _, _) => Ok(ForthVal::Int(*b as i64)),
+            (_, Some(c), None) => Ok(ForthVal::Int(c as i64)),
+            _ => Err(ForthErr::new(ErrKind::Parse, format!("Invalid character {}", token)))
+        }
     }
-    else if FLOAT_RE.is_match(&token){
-        Ok(ForthVal::Float(token.parse().unwrap()))
+    else if token.starts_with("b\"") && STR_RE.is_match(token){
+        Ok(ForthVal::Bytes(unescape_bytes(&token[2..token.len()-1])?))
     }
     else if STR_RE.is_match(&token){
-        // TODO better unescape
-        // I think forth also usually has it has a ." String " sequence
-        Ok(ForthVal::Str(token[1..token.len()-1].to_string()))
+        Ok(ForthVal::Str(unescape(&token[1..token.len()-1])?))
     }
     else{
         let t = token.to_string();
@@ -69,8 +224,9 @@ fn read_atom(token: &String) -> ForthRet{
             Ok(ForthVal::Sym(t))
         }
         else if t.contains("."){
-            let sp: Vec<&str> = t.split(".").collect();
-            Ok(ForthVal::Property((sp[0].to_string(), sp[1].to_string())))
+            // Fields of nested forms are kept as one path
+            let (form, field) = t.split_once('.').unwrap();
+            Ok(ForthVal::Property((form.to_string(), field.to_string())))
         }
         else if t.starts_with("`"){
             Ok(ForthVal::Meta(t[1..].to_string()))
@@ -88,9 +244,20 @@ fn read_list(reader: &mut ForthReader) -> ForthRet{
     loop{
         let token = reader.get_token()?;
         
+        // Lists hold values, so numbers are read in any base
         let val = match &token[..]{
             "]" => return Ok(ForthVal::List(mlist)),
-            _ => read_atom(&token)?
+            "[" => {
+                reader.step();
+                read_list(reader)?
+            },
+            "~" if reader.peek_token(1) == Some("[") => {
+                return Err(ForthErr::new(ErrKind::Parse, "Evaluated list inside list literal, use ~[ for the outer list"));
+            },
+            _ => match parse_number(&token, reader.base)?{
+                Some(v) => v,
+                None => read_atom(&token, reader.base)?
+            }
         };
         
         mlist.push(val);
@@ -109,6 +276,20 @@ fn read_token(reader: &mut ForthReader) -> ForthRet{
     let token = reader.get_token()?;
     
     match &token[..]{
+        // Words made of brackets, these are split by the tokenizer
+        "[" if reader.peek_token(1) == Some(":") => {
+            reader.step();
+            Ok(ForthVal::Sym("[:".to_string()))
+        },
+        "[" if reader.peek_token(1) == Some("'") && reader.peek_token(2) == Some("]") => {
+            reader.step();
+            reader.step();
+            Ok(ForthVal::Sym("[']".to_string()))
+        },
+        ";" if reader.peek_token(1) == Some("]") => {
+            reader.step();
+            Ok(ForthVal::Sym(";]".to_string()))
+        },
         "[" => {
             reader.step();
             read_list(reader)
@@ -117,9 +298,13 @@ fn read_token(reader: &mut ForthReader) -> ForthRet{
             reader.step();
             read_meta(reader)  
         },
-        "]" => Err(ForthErr::ErrString("Got end of list before start of list".to_string())),
-        "\\" => {reader.comment = true; Ok(ForthVal::Null)},
-        _ => read_atom(&token)
+        // Evaluated lists are run as words which collect the stack
+        "~" if reader.peek_token(1) == Some("[") => {
+            reader.step();
+            Ok(ForthVal::Sym("~[".to_string()))
+        },
+        "]" => Ok(ForthVal::Sym(token)),
+        _ => read_atom(&token, reader.base)
     }
 }
 
@@ -127,7 +312,10 @@ fn read_token(reader: &mut ForthReader) -> ForthRet{
 pub struct ForthReader{
     tokens: Vec<String>,
     pos: usize,
-    comment: bool
+    // Inside a ( comment at the end of the line
+    comment: bool,
+    // Number base
+    base: u32
 }
 
 impl ForthReader{
@@ -136,13 +324,18 @@ impl ForthReader{
         Ok(
             self.tokens
                 .get(self.pos)
-                .ok_or_else(|| ForthErr::ErrString("Underflow".to_string()))?
+                .ok_or_else(|| ForthErr::new(ErrKind::Parse, "Unexpected end of input"))?
                 .to_string()
         )
     }
     
+    /// Token n places after the current one
+    fn peek_token(&self, n: usize) -> Option<&str>{
+        self.tokens.get(self.pos + n).map(|t| t.as_str())
+    }
+    
     pub fn is_done(&self) -> bool{
-        self.pos == self.tokens.len() || self.comment || self.tokens[0] == "\\"
+        self.pos == self.tokens.len()
     }
     
     /// Increment position
@@ -150,19 +343,29 @@ impl ForthReader{
         self.pos += 1;
     }
     
-    pub fn from_line(s: &str) -> Self{
+    /// Read a line, comment is true if a ( comment continues from the last line
+    pub fn from_line(s: &str, comment: bool) -> Self{
+        let (tokens, comment) = strip_comments(tokenize(s), comment);
         Self{
-            tokens: tokenize(s),
+            tokens: tokens,
             pos: 0,
-            comment: false
+            comment: comment,
+            base: 10
         }
     }
     
+    /// Check if a ( comment continues on the next line
+    pub fn in_comment(&self) -> bool{
+        self.comment
+    }
+    
     pub fn is_empty(&self) -> bool{
-        self.tokens.len() == 0 || self.tokens[0].starts_with('\\')
+        self.tokens.len() == 0
     }
     
-    pub fn next(&mut self) -> ForthRet{
+    /// Read next value, numbers are read in base
+    pub fn next(&mut self, base: u32) -> ForthRet{
+        self.base = base;
         let ret = read_token(self);
         self.step();
         ret
@@ -1,5 +1,7 @@
-use crate::{interpreter::{ForthRoutine, math}, generator::GeneratorUnit};
+use crate::{interpreter::ForthRoutine, generator::{Combine, GeneratorUnit}};
 use crate::proc::Proc;
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
 
 use crate::interpreter::mem::Location;
 
@@ -8,14 +10,38 @@ pub enum AsmPromise{
     JAL(u32) // destination register
 }
 
+/// Compiled control flow, targets are indices into the compiled program
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flow{
+    Jump(usize),
+    JumpIfZero(usize),
+    // Counted loops
+    Do,
+    QDo(usize),  // skips to target if limit == start
+    Loop(usize), // target is start of loop body
+    PlusLoop(usize),
+    Leave(usize), // target is end of loop
+    // Words
+    Exit,
+    Recurse,
+    // Push the execution token of a word
+    Tick(usize)
+}
+
 /// Forth value
 #[derive(Clone)]
 pub enum ForthVal{
     Null,
     // immediate
     Int(i64),
+    // Arbitrary precision, only made on request
+    Big(BigInt),
     Float(f64),
     Str(String),
+    // Raw bytes, sent as they are
+    Bytes(Vec<u8>),
+    // Output text, printed without quotes
+    Text(String),
     List(Vec<ForthVal>),
     Generator(GeneratorUnit),
     Form(Proc),
@@ -31,7 +57,7 @@ pub enum ForthVal{
     Callable(ForthRoutine),
     // Compiled program
     Func(usize),
-    Err(String)
+    Flow(Flow)
 }
 
 impl std::fmt::Debug for ForthVal{
@@ -40,10 +66,13 @@ impl std::fmt::Debug for ForthVal{
     }
 }
 
-pub type IntOp = fn(&i64, &i64) -> i64;
+pub type IntOp = fn(&i64, &i64) -> Result<i64, ForthErr>;
 pub type FloatOp = fn(&f64, &f64) -> f64;
+pub type BigOp = fn(&BigInt, &BigInt) -> Result<BigInt, ForthErr>;
+pub type UnaryIntOp = fn(&i64) -> Result<i64, ForthErr>;
+pub type UnaryFloatOp = fn(&f64) -> f64;
 
-fn operate_list(contents: &Vec<ForthVal>, other: &ForthVal, fi: IntOp, ff: FloatOp, reverse: bool) -> ForthRet{
+fn operate_list(contents: &Vec<ForthVal>, other: &ForthVal, fi: IntOp, ff: FloatOp, fb: Option<BigOp>, reverse: bool) -> ForthRet{
     let mut result = Vec::new();
     match other{
         ForthVal::List(other_contents) => {
@@ -51,20 +80,20 @@ fn operate_list(contents: &Vec<ForthVal>, other: &ForthVal, fi: IntOp, ff: Float
             let n = std::cmp::min(contents.len(), other_contents.len());
             for i in 0..n{
                 if reverse{
-                    result.push(other_contents[i].operate(&contents[i], fi, ff).unwrap());
+                    result.push(other_contents[i].operate(&contents[i], fi, ff, fb)?);
                 }
                 else{
-                    result.push(contents[i].operate(&other_contents[i], fi, ff).unwrap());
+                    result.push(contents[i].operate(&other_contents[i], fi, ff, fb)?);
                 }
             }
         },
         _ => {
             for c in contents{
                 if reverse{
-                    result.push(other.operate(c, fi, ff).unwrap());
+                    result.push(other.operate(c, fi, ff, fb)?);
                 }
                 else{
-                    result.push(c.operate(other, fi, ff).unwrap());
+                    result.push(c.operate(other, fi, ff, fb)?);
                 }
             }
         }
@@ -78,21 +107,23 @@ impl ForthVal{
         match self{
             ForthVal::Null => "None".to_string(),
             ForthVal::Int(v) => format!("{}", v),
+            ForthVal::Big(v) => format!("{}n", v),
             ForthVal::Float(f) => format!("{:.4}", f),
             ForthVal::Str(s) => format!("'{}'", s),
+            ForthVal::Bytes(b) => format!("b\"{}\"", b.escape_ascii()),
+            ForthVal::Text(s) => s.clone(),
             ForthVal::Sym(s) => format!("{}", s),
             ForthVal::List(v) => format!("{:?}", v),
             ForthVal::Meta(v) => format!("Function {}", v),
-            ForthVal::Err(e) => format!("Error: {}", e),
             ForthVal::Func(id) => format!("Function with id {}", id),
+            ForthVal::Callable(_) => format!("Quotation"),
+            ForthVal::Flow(f) => format!("Flow {:?}", f),
             ForthVal::Promise((name, promise)) => {
                 match promise{
                     AsmPromise::JAL(rd) => format!("JAL -> ({}) (x{})", name, rd)
                 }
             }
-            ForthVal::Form(_proc) => {
-              format!("Process")
-            },
+            ForthVal::Form(proc) => proc.to_string(),
             ForthVal::Var(loc) => {
                 match loc{
                     Location::Local(a) => format!("Var at local address {}", a),
@@ -103,6 +134,15 @@ impl ForthVal{
                 let mut g = gen.clone();
                 let mut v = String::new();
                 v.push_str("{");
+                // Finite generators are shown whole if they are short
+                if g.remaining().is_some_and(|n| n <= 9){
+                    let samples: Vec<String> = (0..g.remaining().unwrap_or(0))
+                        .map(|_| g.next().to_string())
+                        .collect();
+                    v.push_str(&samples.join(", "));
+                    v.push('}');
+                    return v;
+                }
                 for _i in 0..9{
                     v.push_str(format!("{}, ", g.next().to_string()).as_str());
                 }
@@ -113,6 +153,110 @@ impl ForthVal{
         }
     }
     
+    /// Format with integers in radix, other values as normal
+    pub fn to_string_radix(&self, radix: u32) -> String{
+        match self{
+            ForthVal::Int(v) => {
+                let mut digits = Vec::new();
+                let mut n = v.unsigned_abs();
+                loop{
+                    digits.push(std::char::from_digit((n % radix as u64) as u32, radix).unwrap());
+                    n /= radix as u64;
+                    if n == 0{
+                        break;
+                    }
+                }
+                if *v < 0{
+                    digits.push('-');
+                }
+                digits.iter().rev().collect()
+            },
+            ForthVal::Big(v) => format!("{}n", v.to_str_radix(radix)),
+            ForthVal::List(values) => {
+                let items: Vec<String> = values.iter().map(|v| v.to_string_radix(radix)).collect();
+                format!("[{}]", items.join(", "))
+            },
+            _ => self.to_string()
+        }
+    }
+    
+    /// Order numbers, strings and lists of them
+    pub fn compare(&self, other: &ForthVal) -> Result<std::cmp::Ordering, ForthErr>{
+        match (self, other){
+            (ForthVal::Int(a), ForthVal::Int(b)) => Ok(a.cmp(b)),
+            (ForthVal::Int(_) | ForthVal::Big(_), ForthVal::Int(_) | ForthVal::Big(_)) => {
+                Ok(self.to_big()?.cmp(&other.to_big()?))
+            },
+            (ForthVal::Int(_) | ForthVal::Float(_) | ForthVal::Big(_), ForthVal::Int(_) | ForthVal::Float(_) | ForthVal::Big(_)) => {
+                Ok(self.to_float()?.total_cmp(&other.to_float()?))
+            },
+            (ForthVal::Str(a), ForthVal::Str(b)) => Ok(a.cmp(b)),
+            (ForthVal::Null, ForthVal::Null) => Ok(std::cmp::Ordering::Equal),
+            (ForthVal::List(a), ForthVal::List(b)) => {
+                for (x, y) in a.iter().zip(b.iter()){
+                    match x.compare(y)?{
+                        std::cmp::Ordering::Equal => (),
+                        order => {return Ok(order);}
+                    }
+                }
+                Ok(a.len().cmp(&b.len()))
+            },
+            _ => Err(ForthErr::new(ErrKind::TypeMismatch,
+                format!("can't compare {} with {}", self.type_name(), other.type_name())))
+        }
+    }
+    
+    /// Check if values are equal, floats can differ by up to tolerance
+    /// Values of different types are not equal
+    pub fn equals_within(&self, other: &ForthVal, tolerance: f64) -> bool{
+        match (self, other){
+            (ForthVal::Int(a), ForthVal::Int(b)) => a == b,
+            (ForthVal::Int(_) | ForthVal::Big(_), ForthVal::Int(_) | ForthVal::Big(_)) => {
+                matches!((self.to_big(), other.to_big()), (Ok(a), Ok(b)) if a == b)
+            },
+            (ForthVal::Int(_) | ForthVal::Float(_) | ForthVal::Big(_), ForthVal::Int(_) | ForthVal::Float(_) | ForthVal::Big(_)) => {
+                match (self.to_float(), other.to_float()){
+                    (Ok(a), Ok(b)) => a == b || (a - b).abs() <= tolerance,
+                    _ => false
+                }
+            },
+            (ForthVal::List(a), ForthVal::List(b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| x.equals_within(y, tolerance))
+            },
+            (ForthVal::Null, ForthVal::Null) => true,
+            (ForthVal::Str(a), ForthVal::Str(b)) => a == b,
+            (ForthVal::Bytes(a), ForthVal::Bytes(b)) => a == b,
+            (ForthVal::Sym(a), ForthVal::Sym(b)) => a == b,
+            (ForthVal::Func(a), ForthVal::Func(b)) => a == b,
+            _ => false
+        }
+    }
+    
+    /// Name of the value type for error messages
+    pub fn type_name(&self) -> &str{
+        match self{
+            ForthVal::Null => "none",
+            ForthVal::Int(_) => "int",
+            ForthVal::Big(_) => "big int",
+            ForthVal::Float(_) => "float",
+            ForthVal::Str(_) => "string",
+            ForthVal::Bytes(_) => "bytes",
+            ForthVal::Text(_) => "text",
+            ForthVal::List(_) => "list",
+            ForthVal::Generator(_) => "generator",
+            ForthVal::Form(_) => "form",
+            ForthVal::Property(_) => "property",
+            ForthVal::Var(_) => "variable",
+            ForthVal::Promise(_) => "promise",
+            ForthVal::Sym(_) => "symbol",
+            ForthVal::Vector(_) => "vector",
+            ForthVal::Meta(_) => "meta",
+            ForthVal::Callable(_) => "callable",
+            ForthVal::Func(_) => "function",
+            ForthVal::Flow(_) => "flow"
+        }
+    }
+    
     pub fn size(&self) -> usize{
         1 // STUB
     }
@@ -120,7 +264,28 @@ impl ForthVal{
     pub fn to_int(&self) -> Result<i64, ForthErr>{
         match self{
             ForthVal::Int(v) => Ok(v.clone()),
-            _ => Err(ForthErr::ErrForthVal(self.clone()))
+            ForthVal::Big(v) => v.to_i64().ok_or_else(|| {
+                ForthErr::new(ErrKind::Overflow, format!("{} does not fit in 64 bits", v))
+            }),
+            _ => Err(ForthErr::mismatch("int", self))
+        }
+    }
+    
+    /// Bits of a 32 bit word, either signed or unsigned
+    pub fn to_u32(&self) -> Result<u32, ForthErr>{
+        let v = self.to_int()?;
+        if v < i32::MIN as i64 || v > u32::MAX as i64{
+            return Err(ForthErr::new(ErrKind::Invalid, format!("{} does not fit in 32 bits", v)));
+        }
+        Ok(v as u32)
+    }
+    
+    /// Int as a big int
+    pub fn to_big(&self) -> Result<BigInt, ForthErr>{
+        match self{
+            ForthVal::Int(v) => Ok(BigInt::from(*v)),
+            ForthVal::Big(v) => Ok(v.clone()),
+            _ => Err(ForthErr::mismatch("int", self))
         }
     }
     
@@ -128,70 +293,67 @@ impl ForthVal{
         match self{
             ForthVal::Float(f) => Ok(f.clone()),
             ForthVal::Int(v) => Ok(v.clone() as f64),
-            _ => Err(ForthErr::ErrForthVal(self.clone()))
+            ForthVal::Big(v) => Ok(v.to_f64().unwrap_or(f64::NAN)),
+            _ => Err(ForthErr::mismatch("number", self))
         }
     }
     
-    /// Add
-    pub fn operate(&self, other: &ForthVal, fi: IntOp, ff: FloatOp) -> ForthRet{
-        match self{
-            ForthVal::Int(a) => {
-                match other{
-                    ForthVal::Int(b) => Ok(ForthVal::Int(fi(a, b))),
-                    ForthVal::Float(b) => Ok(ForthVal::Int(fi(a, &(b.round() as i64)))),
-                    ForthVal::List(b) =>  operate_list(b, self, fi, ff, true),
-                    ForthVal::Generator(gen) => {
-                      let gn = gen;
-                      Ok(ForthVal::Generator(
-                          gn.clone()
-                            .push(self)
-                            .push(&ForthVal::Callable(
-                                ForthRoutine::Prim(
-                                    math::binary_op(fi, ff)
-                                )
-                            )).clone()
-                        )) 
-                    },
-                    _ => Err(ForthErr::ErrString("Can't add with int".to_string()))
+    /// Operate on two values, self is the top of the stack
+    /// Big ints need fb, other ints are promoted to big ints to meet them
+    pub fn operate(&self, other: &ForthVal, fi: IntOp, ff: FloatOp, fb: Option<BigOp>) -> ForthRet{
+        match (self, other){
+            (ForthVal::Int(a), ForthVal::Int(b)) => Ok(ForthVal::Int(fi(a, b)?)),
+            (ForthVal::Int(_) | ForthVal::Big(_), ForthVal::Int(_) | ForthVal::Big(_)) => {
+                match fb{
+                    Some(fb) => Ok(ForthVal::Big(fb(&self.to_big()?, &other.to_big()?)?)),
+                    None => {
+                        let big = if let ForthVal::Big(_) = self {self} else {other};
+                        Err(ForthErr::mismatch("int", big))
+                    }
                 }
             },
-            ForthVal::Float(a) => {
-                match other{
-                    ForthVal::Int(b) => Ok(ForthVal::Float(ff(a, &(*b as f64)))),
-                    ForthVal::Float(b) => Ok(ForthVal::Float(ff(a, b))),
-                    ForthVal::List(b) =>  operate_list(b, self, fi, ff, true),
-                    ForthVal::Generator(gen) => {
-                      let gn = gen;
-                      Ok(ForthVal::Generator(
-                          gn.clone()
-                            .push(self)
-                            .push(&ForthVal::Callable(
-                                ForthRoutine::Prim(
-                                    math::binary_op(fi, ff)
-                                )
-                            )).clone()
-                        )) 
-                    },
-                    _ => Err(ForthErr::ErrString("Can't add with int".to_string()))
-                }
+            (ForthVal::Int(a), ForthVal::Float(b)) => Ok(ForthVal::Int(fi(a, &(b.round() as i64))?)),
+            (ForthVal::Float(_) | ForthVal::Big(_), ForthVal::Int(_) | ForthVal::Float(_) | ForthVal::Big(_)) => {
+                Ok(ForthVal::Float(ff(&self.to_float()?, &other.to_float()?)))
             },
-            ForthVal::List(contents) =>{
-                operate_list(contents, other, fi, ff, false)
+            (ForthVal::Int(_) | ForthVal::Float(_) | ForthVal::Big(_) | ForthVal::Generator(_), ForthVal::List(b)) => {
+                operate_list(b, self, fi, ff, fb, true)
             },
-            ForthVal::Generator(gen) => {
-                let gn = gen.clone();
-                Ok(ForthVal::Generator(
-                    gn.clone()
-                        .push(other)
-                        .push(&ForthVal::Callable(
-                            ForthRoutine::Prim(
-                                math::binary_op(fi, ff))
-                            )
-                        )
-                        .clone()
-                ))
-            }
-            _ => Err(ForthErr::ErrString(format!("Can't add {:?}, {:?}", self, other)))
+            // Generator samples are floats, so constants are applied as floats
+            (ForthVal::Int(_) | ForthVal::Float(_) | ForthVal::Big(_), ForthVal::Generator(gen)) => {
+                Ok(ForthVal::Generator(gen.clone().push_binary(self.to_float()?, ff, false).clone()))
+            },
+            (ForthVal::List(contents), _) => operate_list(contents, other, fi, ff, fb, false),
+            (ForthVal::Generator(a), ForthVal::Generator(b)) => {
+                Ok(ForthVal::Generator(GeneratorUnit::new(Box::new(Combine::new(a.clone(), b.clone(), ff)))))
+            },
+            (ForthVal::Generator(gen), ForthVal::Int(_) | ForthVal::Float(_) | ForthVal::Big(_)) => {
+                Ok(ForthVal::Generator(gen.clone().push_binary(other.to_float()?, ff, true).clone()))
+            },
+            (ForthVal::Int(_) | ForthVal::Float(_) | ForthVal::Big(_) | ForthVal::Generator(_), _) => {
+                Err(ForthErr::mismatch("number", other))
+            },
+            _ => Err(ForthErr::mismatch("number", self))
+        }
+    }
+    
+    /// Apply to each number, ints become floats if there is no int operation
+    pub fn operate_unary(&self, fi: Option<UnaryIntOp>, ff: UnaryFloatOp) -> ForthRet{
+        match self{
+            ForthVal::Int(a) => match fi{
+                Some(fi) => Ok(ForthVal::Int(fi(a)?)),
+                None => Ok(ForthVal::Float(ff(&(*a as f64))))
+            },
+            ForthVal::Float(a) => Ok(ForthVal::Float(ff(a))),
+            ForthVal::List(contents) => {
+                let mut result = Vec::new();
+                for c in contents{
+                    result.push(c.operate_unary(fi, ff)?);
+                }
+                Ok(ForthVal::List(result))
+            },
+            ForthVal::Generator(gen) => Ok(ForthVal::Generator(gen.clone().push_unary(ff).clone())),
+            _ => Err(ForthErr::mismatch("number", self))
         }
     }
     
@@ -213,12 +375,150 @@ impl ForthVal{
     }
 }
 
-/// Error enumerator
-#[derive(Debug)]
-pub enum ForthErr{
-    ErrString(String),
-    ErrForthVal(ForthVal)
+/// Kind of error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrKind{
+    Underflow,
+    TypeMismatch,
+    UnknownWord,
+    Compile,
+    Parse,
+    ReturnStack,
+    Index,
+    Invalid,
+    Device,
+    File,
+    Assert,
+    CallDepth,
+    DivideByZero,
+    Overflow,
+    Range,
+    // Raised by throw and abort, with the throw code
+    Throw(i64)
+}
+
+impl ErrKind{
+    pub fn describe(&self) -> &str{
+        match self{
+            ErrKind::Underflow => "Stack underflow",
+            ErrKind::TypeMismatch => "Type mismatch",
+            ErrKind::UnknownWord => "Unknown word",
+            ErrKind::Compile => "Compile error",
+            ErrKind::Parse => "Parse error",
+            ErrKind::ReturnStack => "Return stack error",
+            ErrKind::Index => "Index error",
+            ErrKind::Invalid => "Invalid argument",
+            ErrKind::Device => "Device error",
+            ErrKind::File => "File error",
+            ErrKind::Assert => "Assertion failed",
+            ErrKind::CallDepth => "Call depth exceeded",
+            ErrKind::DivideByZero => "Division by zero",
+            ErrKind::Overflow => "Result out of range",
+            ErrKind::Range => "Argument out of range",
+            ErrKind::Throw(-1) | ErrKind::Throw(-2) => "Aborted",
+            ErrKind::Throw(_) => "Exception"
+        }
+    }
+    
+    /// Code given to catch, negative codes follow standard forth where possible
+    pub fn code(&self) -> i64{
+        match self{
+            ErrKind::Underflow => -4,
+            ErrKind::CallDepth => -5,
+            ErrKind::Index => -9,
+            ErrKind::DivideByZero => -10,
+            ErrKind::Overflow => -11,
+            ErrKind::UnknownWord => -13,
+            ErrKind::Compile => -22,
+            ErrKind::Invalid | ErrKind::Range => -24,
+            ErrKind::ReturnStack => -25,
+            ErrKind::File => -37,
+            ErrKind::TypeMismatch => -256,
+            ErrKind::Parse => -257,
+            ErrKind::Device => -258,
+            ErrKind::Assert => -259,
+            ErrKind::Throw(n) => *n
+        }
+    }
+}
+
+/// Error raised while reading or running forth
+#[derive(Debug, Clone)]
+pub struct ForthErr{
+    pub kind: ErrKind,
+    pub msg: String,
+    // Word which raised the error
+    pub word: Option<String>,
+    // Compiled words the error passed through, innermost first
+    pub trace: Vec<String>
+}
+
+impl ForthErr{
+    pub fn new(kind: ErrKind, msg: impl Into<String>) -> Self{
+        Self{
+            kind: kind,
+            msg: msg.into(),
+            word: None,
+            trace: Vec::new()
+        }
+    }
+    
+    pub fn underflow() -> Self{
+        Self::new(ErrKind::Underflow, "")
+    }
+    
+    pub fn overflow() -> Self{
+        Self::new(ErrKind::Overflow, "integer overflow")
+    }
+    
+    pub fn divide_by_zero() -> Self{
+        Self::new(ErrKind::DivideByZero, "")
+    }
+    
+    /// Value was not of the expected type
+    pub fn mismatch(expected: &str, got: &ForthVal) -> Self{
+        Self::new(ErrKind::TypeMismatch, 
+            format!("expected {}, got {} {}", expected, got.type_name(), got.to_string()))
+    }
+    
+    /// Set word which raised the error, if not already set
+    pub fn in_word(mut self, word: &str) -> Self{
+        if self.word.is_none(){
+            self.word = Some(word.to_string());
+        }
+        self
+    }
+    
+    /// Add compiled word to the call chain
+    /// Recursive calls are only listed once
+    pub fn called_from(mut self, word: &str) -> Self{
+        let last = self.trace.last().or(self.word.as_ref());
+        match last{
+            None => {self.word = Some(word.to_string());},
+            Some(w) if w == word => (),
+            Some(_) => {self.trace.push(word.to_string());}
+        }
+        self
+    }
+}
+
+impl std::fmt::Display for ForthErr{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind.describe())?;
+        if let Some(word) = &self.word{
+            write!(f, " in {}", word)?;
+        }
+        if !self.msg.is_empty(){
+            write!(f, ": {}", self.msg)?;
+        }
+        if !self.trace.is_empty(){
+            write!(f, " (called from {})", self.trace.join(" <- "))?;
+        }
+        Ok(())
+    }
 }
 
+impl std::error::Error for ForthErr{}
+
 pub type ForthRet = Result<ForthVal, ForthErr>;
 